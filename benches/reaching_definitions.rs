@@ -0,0 +1,108 @@
+// 10万文のプログラムで到達定義を計算し、BitSetとHashSetで時間を比べる
+//
+//   cargo bench --bench reaching_definitions
+
+use opt_for_lang2::ir::{Expr, Label, Stmt, Var};
+use opt_for_lang2::{code_to_graph, solve, Analysis, Direction, ReachingDefinitions};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+const STMTS: usize = 100_000;
+// この数の文ごとに、読んだ値が0でなければ変数に代入する
+const STMTS_PER_DEF: usize = 500;
+const VARIABLES: usize = 10;
+
+// 代入が飛ばされる可能性があるので、定義は後ろの文まで到達し続ける
+fn generate() -> Vec<Stmt> {
+    let vars: Vec<Var> = (0..VARIABLES)
+        .map(|i| Var::new(&format!("v{}", i)))
+        .collect();
+
+    let mut code = Vec::with_capacity(STMTS);
+    while code.len() < STMTS {
+        let k = code.len() / STMTS_PER_DEF;
+        let var = vars[k % VARIABLES];
+        let skip = Label::new();
+        code.push(Stmt::JumpIfZero(Expr::Read, skip));
+        code.push(Stmt::Store(var, Expr::Int(k as i64)));
+        code.push(Stmt::Label(skip));
+        while code.len() < STMTS && code.len() % STMTS_PER_DEF != 0 {
+            code.push(Stmt::Print(Expr::LoadCopy(var)));
+        }
+    }
+
+    code
+}
+
+// BitSetを使う前と同じ、文の位置のHashSetによる到達定義
+struct HashSetReachingDefinitions {
+    defs: HashMap<Var, HashSet<usize>>,
+}
+
+impl Analysis<Stmt> for HashSetReachingDefinitions {
+    type Domain = HashSet<usize>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> HashSet<usize> {
+        HashSet::new()
+    }
+
+    fn initial(&self) -> HashSet<usize> {
+        HashSet::new()
+    }
+
+    fn meet(&self, acc: &mut HashSet<usize>, other: &HashSet<usize>) {
+        *acc = &*acc | other;
+    }
+
+    fn transfer(&self, index: usize, stmt: &Stmt, input: &HashSet<usize>) -> HashSet<usize> {
+        match stmt {
+            Stmt::Store(var, _) => {
+                let mut output = input - &self.defs[var];
+                output.insert(index);
+                output
+            }
+            _ => input.clone(),
+        }
+    }
+}
+
+fn measure<F: FnMut() -> usize>(name: &str, mut f: F) -> Duration {
+    let start = Instant::now();
+    let reached = f();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<8} {:>10.2?}  (reached definitions: {})",
+        name, elapsed, reached
+    );
+    elapsed
+}
+
+fn main() {
+    let code = code_to_graph(generate());
+    println!("{} statements", code.len());
+
+    let bitset = measure("BitSet", || {
+        let analysis = ReachingDefinitions::new(&code);
+        let result = solve(&code, &analysis);
+        result.ins.iter().map(|defs| defs.len()).sum()
+    });
+
+    let hashset = measure("HashSet", || {
+        let mut defs: HashMap<Var, HashSet<usize>> = HashMap::new();
+        for (i, stmt) in code.iter().enumerate() {
+            if let Stmt::Store(var, _) = stmt {
+                defs.entry(*var).or_default().insert(i);
+            }
+        }
+
+        let result = solve(&code, &HashSetReachingDefinitions { defs });
+        result.ins.iter().map(|defs| defs.len()).sum()
+    });
+
+    println!(
+        "speedup: {:.1}x",
+        hashset.as_secs_f64() / bitset.as_secs_f64()
+    );
+}
@@ -0,0 +1,248 @@
+use std::fmt;
+use std::iter::FromIterator;
+use std::ops::{BitAnd, BitOr, Sub};
+
+const WORD_BITS: usize = 64;
+
+// 非負整数の密な集合
+// データフロー解析の集合演算を、要素ごとではなく64ビットずつ行う
+#[derive(Clone, Default)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    pub fn new() -> Self {
+        Self { words: Vec::new() }
+    }
+
+    // capacity未満の要素を入れても確保し直さない
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            words: Vec::with_capacity(capacity.div_ceil(WORD_BITS)),
+        }
+    }
+
+    // 要素が新しく追加されたらtrueを返す
+    pub fn insert(&mut self, value: usize) -> bool {
+        let (word, bit) = (value / WORD_BITS, value % WORD_BITS);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+
+        let inserted = self.words[word] & (1 << bit) == 0;
+        self.words[word] |= 1 << bit;
+        inserted
+    }
+
+    // 要素が削除されたらtrueを返す
+    pub fn remove(&mut self, value: usize) -> bool {
+        let removed = self.contains(value);
+        if removed {
+            self.words[value / WORD_BITS] &= !(1 << (value % WORD_BITS));
+        }
+        removed
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        match self.words.get(value / WORD_BITS) {
+            Some(word) => word & (1 << (value % WORD_BITS)) != 0,
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
+
+    pub fn clear(&mut self) {
+        self.words.clear();
+    }
+
+    // 集合が変わったらtrueを返す
+    pub fn union_with(&mut self, other: &BitSet) -> bool {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+
+        let mut changed = false;
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            let new_word = *word | other;
+            changed |= new_word != *word;
+            *word = new_word;
+        }
+        changed
+    }
+
+    pub fn intersect_with(&mut self, other: &BitSet) {
+        self.words.truncate(other.words.len());
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    pub fn difference_with(&mut self, other: &BitSet) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            words: &self.words,
+            index: 0,
+            word: self.words.first().copied().unwrap_or(0),
+        }
+    }
+}
+
+// 末尾の0の語の数によらず、要素が同じであれば等しい
+impl PartialEq for BitSet {
+    fn eq(&self, other: &Self) -> bool {
+        let (short, long) = if self.words.len() <= other.words.len() {
+            (&self.words, &other.words)
+        } else {
+            (&other.words, &self.words)
+        };
+
+        short == &long[..short.len()] && long[short.len()..].iter().all(|word| *word == 0)
+    }
+}
+
+impl Eq for BitSet {}
+
+impl fmt::Debug for BitSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+// 要素を小さい順に返す
+#[derive(Clone)]
+pub struct Iter<'a> {
+    words: &'a [u64],
+    index: usize,
+    // まだ返していない要素が残っている、現在の語
+    word: u64,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.word == 0 {
+            self.index += 1;
+            self.word = *self.words.get(self.index)?;
+        }
+
+        let bit = self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        Some(self.index * WORD_BITS + bit)
+    }
+}
+
+impl<'a> IntoIterator for &'a BitSet {
+    type Item = usize;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl FromIterator<usize> for BitSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut set = BitSet::new();
+        for value in iter {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+impl Extend<usize> for BitSet {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+impl BitAnd for &BitSet {
+    type Output = BitSet;
+
+    fn bitand(self, rhs: &BitSet) -> BitSet {
+        let mut set = self.clone();
+        set.intersect_with(rhs);
+        set
+    }
+}
+
+impl BitOr for &BitSet {
+    type Output = BitSet;
+
+    fn bitor(self, rhs: &BitSet) -> BitSet {
+        let mut set = self.clone();
+        set.union_with(rhs);
+        set
+    }
+}
+
+impl Sub for &BitSet {
+    type Output = BitSet;
+
+    fn sub(self, rhs: &BitSet) -> BitSet {
+        let mut set = self.clone();
+        set.difference_with(rhs);
+        set
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove() {
+        let mut set = BitSet::new();
+        assert!(set.insert(3));
+        assert!(set.insert(200));
+        assert!(!set.insert(3));
+        assert!(set.contains(200));
+        assert!(!set.contains(199));
+        assert_eq!(set.len(), 2);
+
+        assert!(set.remove(200));
+        assert!(!set.remove(1000));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![3]);
+
+        // 確保した語の数によらず比較できる
+        assert_eq!(set, [3].iter().copied().collect());
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a: BitSet = vec![0, 1, 64, 130].into_iter().collect();
+        let b: BitSet = vec![1, 64, 65].into_iter().collect();
+
+        assert_eq!((&a & &b).iter().collect::<Vec<_>>(), vec![1, 64]);
+        assert_eq!(
+            (&a | &b).iter().collect::<Vec<_>>(),
+            vec![0, 1, 64, 65, 130]
+        );
+        assert_eq!((&a - &b).iter().collect::<Vec<_>>(), vec![0, 130]);
+        assert_eq!((&b - &a).iter().collect::<Vec<_>>(), vec![65]);
+
+        let mut c = b.clone();
+        assert!(!c.union_with(&BitSet::new()));
+        assert!(c.union_with(&a));
+        assert_eq!(c.len(), 5);
+    }
+}
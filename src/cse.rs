@@ -0,0 +1,376 @@
+use crate::bitset::BitSet;
+use crate::code_to_graph;
+use crate::dataflow::{self, Analysis, Direction};
+use crate::graph::DirectedGraph;
+use crate::ir::{Expr, Stmt, Var};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+// 式を構造で比較するためのキー
+// 浮動小数点数はビット列で比べるので、0.0と-0.0は別の式になり、NaNは自身と等しくなる
+pub(crate) struct ExprKey(Expr);
+
+pub(crate) fn same_expr(a: &Expr, b: &Expr) -> bool {
+    if mem::discriminant(a) != mem::discriminant(b) {
+        return false;
+    }
+
+    let same_leaf = match (a, b) {
+        (Expr::Float(a), Expr::Float(b)) => a.to_bits() == b.to_bits(),
+        (Expr::Convert(a, _), Expr::Convert(b, _)) => a == b,
+        (Expr::Call(a, _), Expr::Call(b, _)) => a == b,
+        (a, b) if a.subexprs().is_empty() => a == b,
+        _ => true,
+    };
+    let (a, b) = (a.subexprs(), b.subexprs());
+    same_leaf && a.len() == b.len() && a.iter().zip(&b).all(|(a, b)| same_expr(a, b))
+}
+
+fn hash_expr<H: Hasher>(expr: &Expr, state: &mut H) {
+    mem::discriminant(expr).hash(state);
+    match expr {
+        Expr::Int(n) => n.hash(state),
+        Expr::Bool(b) => b.hash(state),
+        Expr::Float(x) => x.to_bits().hash(state),
+        Expr::LoadCopy(var) => var.hash(state),
+        Expr::Convert(ty, _) => ty.hash(state),
+        Expr::Call(name, _) => name.hash(state),
+        Expr::Str(s) => s.hash(state),
+        _ => {}
+    }
+
+    for expr in expr.subexprs() {
+        hash_expr(expr, state);
+    }
+}
+
+impl PartialEq for ExprKey {
+    fn eq(&self, other: &Self) -> bool {
+        same_expr(&self.0, &other.0)
+    }
+}
+
+impl Eq for ExprKey {}
+
+impl Hash for ExprKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_expr(&self.0, state);
+    }
+}
+
+// 計算を使い回せる式かどうか
+// グローバル変数は同じ文の中の呼び出しで書き換えられうるので、それを読む式は対象にしない
+fn is_candidate(expr: &Expr) -> bool {
+    !expr.subexprs().is_empty()
+        && expr.is_pure()
+        && !expr.is_const()
+        && expr.used_vars().iter().all(|var| !var.is_global())
+}
+
+// コードに現れる、計算を使い回せる式に番号を付けたもの
+pub(crate) struct ExprTable {
+    exprs: Vec<Expr>,
+    indexes: HashMap<ExprKey, usize>,
+    // 変数ごとの、その変数を読む式の集合
+    users: HashMap<Var, BitSet>,
+}
+
+impl ExprTable {
+    pub(crate) fn new<'a, I: IntoIterator<Item = &'a Stmt>>(code: I) -> Self {
+        let mut table = Self {
+            exprs: Vec::new(),
+            indexes: HashMap::new(),
+            users: HashMap::new(),
+        };
+        for stmt in code {
+            for expr in stmt.exprs() {
+                table.add(expr);
+            }
+        }
+
+        table
+    }
+
+    fn add(&mut self, expr: &Expr) {
+        for expr in expr.subexprs() {
+            self.add(expr);
+        }
+
+        if !is_candidate(expr) || self.index(expr).is_some() {
+            return;
+        }
+
+        let index = self.exprs.len();
+        for var in expr.used_vars() {
+            self.users.entry(var).or_default().insert(index);
+        }
+        self.exprs.push(expr.clone());
+        self.indexes.insert(ExprKey(expr.clone()), index);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.exprs.len()
+    }
+
+    pub(crate) fn expr(&self, index: usize) -> &Expr {
+        &self.exprs[index]
+    }
+
+    pub(crate) fn index(&self, expr: &Expr) -> Option<usize> {
+        // キーを作るために複製しないよう、候補でない式は先に除く
+        if !is_candidate(expr) {
+            return None;
+        }
+        self.indexes.get(&ExprKey(expr.clone())).copied()
+    }
+
+    // 式の中で計算される式の集合
+    pub(crate) fn computed(&self, expr: &Expr, set: &mut BitSet) {
+        for expr in expr.subexprs() {
+            self.computed(expr, set);
+        }
+        if let Some(index) = self.index(expr) {
+            set.insert(index);
+        }
+    }
+
+    // 文を実行した後で値が変わる可能性のある式の集合
+    pub(crate) fn killed(&self, stmt: &Stmt) -> BitSet {
+        let vars = match stmt {
+            Stmt::Store(var, _) => vec![*var],
+            Stmt::EndScope(vars) => vars.clone(),
+            _ => Vec::new(),
+        };
+
+        let mut killed = BitSet::new();
+        for users in vars.iter().filter_map(|var| self.users.get(var)) {
+            killed.union_with(users);
+        }
+        killed
+    }
+}
+
+// 利用可能式解析。値は全ての経路で計算済みで、その後に値が変わっていない式の集合
+// lower_control_flow()で変換した後のコードを対象にする
+pub struct AvailableExpressions {
+    table: ExprTable,
+    gens: Vec<BitSet>,
+    kills: Vec<BitSet>,
+}
+
+impl AvailableExpressions {
+    pub fn new(code: &DirectedGraph<Stmt>) -> Self {
+        let table = ExprTable::new(code.iter());
+
+        let mut gens = Vec::with_capacity(code.len());
+        let mut kills = Vec::with_capacity(code.len());
+        for stmt in code.iter() {
+            let kill = table.killed(stmt);
+            let mut gen = BitSet::new();
+            for expr in stmt.exprs() {
+                table.computed(expr, &mut gen);
+            }
+            // v <- v + 1のように、計算した式が代入で変わることがある
+            gen.difference_with(&kill);
+
+            gens.push(gen);
+            kills.push(kill);
+        }
+
+        Self { table, gens, kills }
+    }
+}
+
+impl Analysis<Stmt> for AvailableExpressions {
+    type Domain = BitSet;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Domain {
+        BitSet::new()
+    }
+
+    fn initial(&self) -> Self::Domain {
+        (0..self.table.len()).collect()
+    }
+
+    fn meet(&self, acc: &mut Self::Domain, other: &Self::Domain) {
+        acc.intersect_with(other);
+    }
+
+    // out = gen U (in - kill)
+    fn transfer(&self, index: usize, _: &Stmt, input: &Self::Domain) -> Self::Domain {
+        let mut output = input.clone();
+        output.difference_with(&self.kills[index]);
+        output.union_with(&self.gens[index]);
+        output
+    }
+}
+
+struct Cse<'a> {
+    table: &'a ExprTable,
+    // 式ごとの、値を入れておく一時変数
+    temps: Vec<Option<Var>>,
+}
+
+impl<'a> Cse<'a> {
+    // 計算済みの式がもう一度計算される場所を探す
+    fn find_redundant(&mut self, expr: &Expr, available: &mut BitSet) {
+        if let Some(index) = self.table.index(expr) {
+            if available.contains(index) {
+                if self.temps[index].is_none() {
+                    self.temps[index] = Some(Var::new("cse"));
+                }
+                return;
+            }
+        }
+
+        for expr in expr.subexprs() {
+            self.find_redundant(expr, available);
+        }
+        if let Some(index) = self.table.index(expr) {
+            available.insert(index);
+        }
+    }
+
+    // 計算済みの式を一時変数の複写に置き換え、最初に計算する場所では一時変数に入れる
+    fn rewrite(&self, expr: &mut Expr, available: &mut BitSet, stores: &mut Vec<Stmt>) {
+        let temp = self
+            .table
+            .index(expr)
+            .and_then(|index| self.temps[index].map(|temp| (index, temp)));
+        let (index, temp) = match temp {
+            Some(temp) => temp,
+            None => {
+                for expr in expr.subexprs_mut() {
+                    self.rewrite(expr, available, stores);
+                }
+                return;
+            }
+        };
+
+        if !available.contains(index) {
+            for expr in expr.subexprs_mut() {
+                self.rewrite(expr, available, stores);
+            }
+            let computed = mem::replace(expr, Expr::LoadCopy(temp));
+            stores.push(Stmt::Store(temp, computed));
+            available.insert(index);
+        }
+
+        *expr = Expr::LoadCopy(temp);
+    }
+}
+
+// 大域的な共通部分式の削除
+// 同じ式を二回以上計算する場合、最初の計算で一時変数に入れ、その後は一時変数を読む
+pub fn common_subexpression_elimination(code: Vec<Stmt>) -> Vec<Stmt> {
+    let graph = code_to_graph(code);
+    let analysis = AvailableExpressions::new(&graph);
+    let result = dataflow::solve(&graph, &analysis);
+
+    let mut cse = Cse {
+        table: &analysis.table,
+        temps: vec![None; analysis.table.len()],
+    };
+    for (i, stmt) in graph.iter().enumerate() {
+        let mut available = result.ins[i].clone();
+        for expr in stmt.exprs() {
+            cse.find_redundant(expr, &mut available);
+        }
+    }
+
+    let mut new_code = Vec::with_capacity(graph.len());
+    for (i, mut stmt) in graph.into_iter().enumerate() {
+        let mut available = result.ins[i].clone();
+        let mut stores = Vec::new();
+        for expr in stmt.exprs_mut() {
+            cse.rewrite(expr, &mut available, &mut stores);
+        }
+
+        new_code.extend(stores);
+        new_code.push(stmt);
+    }
+
+    new_code
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Expr::*, Label};
+
+    fn madd(a: Var, b: Var, c: Var) -> Expr {
+        Add(
+            Box::new(Mul(Box::new(LoadCopy(a)), Box::new(LoadCopy(b)))),
+            Box::new(LoadCopy(c)),
+        )
+    }
+
+    #[test]
+    fn test_cse() {
+        let (a, b, c) = (Var::new("a"), Var::new("b"), Var::new("c"));
+        let (x, y) = (Var::new("x"), Var::new("y"));
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // 両方の分岐でa * b + cを計算するので、合流した後では計算済みになる
+        let code = vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Store(x, madd(a, b, c)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Print(madd(a, b, c)),
+            Stmt::Label(l1),
+            Stmt::Store(y, Mul(Box::new(madd(a, b, c)), Box::new(Int(2)))),
+        ];
+
+        let code = common_subexpression_elimination(code);
+        let temp = match &code[1] {
+            Stmt::Store(temp, expr) => {
+                assert_eq!(expr, &madd(a, b, c));
+                *temp
+            }
+            stmt => panic!("unexpected statement: {}", stmt),
+        };
+        assert_eq!(code[2], Stmt::Store(x, LoadCopy(temp)));
+        assert_eq!(code[5], Stmt::Store(temp, madd(a, b, c)));
+        assert_eq!(code[6], Stmt::Print(LoadCopy(temp)));
+        assert_eq!(
+            code[8],
+            Stmt::Store(y, Mul(Box::new(LoadCopy(temp)), Box::new(Int(2))))
+        );
+    }
+
+    #[test]
+    fn test_killed() {
+        let (a, b, c) = (Var::new("a"), Var::new("b"), Var::new("c"));
+        let x = Var::new("x");
+        let code = vec![
+            Stmt::Print(madd(a, b, c)),
+            Stmt::Store(c, madd(a, b, c)),
+            Stmt::Print(madd(a, b, c)),
+            Stmt::Store(x, Div(Box::new(LoadCopy(a)), Box::new(LoadCopy(b)))),
+            Stmt::Print(Div(Box::new(LoadCopy(a)), Box::new(LoadCopy(b)))),
+        ];
+
+        // a * bはcへの代入で変わらないので使い回せる
+        // 0で割る可能性のある除算は使い回さない
+        let code = common_subexpression_elimination(code);
+        let ab = Mul(Box::new(LoadCopy(a)), Box::new(LoadCopy(b)));
+        let (t_ab, t_abc) = match (&code[0], &code[1]) {
+            (Stmt::Store(t_ab, expr), Stmt::Store(t_abc, _)) if expr == &ab => (*t_ab, *t_abc),
+            (stmt, _) => panic!("unexpected statement: {}", stmt),
+        };
+        let t_ab_plus_c = Add(Box::new(LoadCopy(t_ab)), Box::new(LoadCopy(c)));
+        assert_eq!(code[1], Stmt::Store(t_abc, t_ab_plus_c.clone()));
+        assert_eq!(code[2], Stmt::Print(LoadCopy(t_abc)));
+        assert_eq!(code[3], Stmt::Store(c, LoadCopy(t_abc)));
+        assert_eq!(code[4], Stmt::Store(t_abc, t_ab_plus_c));
+        assert_eq!(code[5], Stmt::Print(LoadCopy(t_abc)));
+        assert_eq!(
+            code[7],
+            Stmt::Print(Div(Box::new(LoadCopy(a)), Box::new(LoadCopy(b))))
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use crate::bitset::BitSet;
+use crate::graph::DirectedGraph;
+use crate::ir::{Expr, Stmt, Var};
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // 入口から出口に向かって値を伝える
+    Forward,
+    // 出口から入口に向かって値を伝える
+    Backward,
+}
+
+// データフロー解析の定義
+// 解析ごとに束の値と合流、伝達関数、境界の値を決めれば、solve()で解ける
+pub trait Analysis<T> {
+    type Domain: Clone + PartialEq;
+
+    const DIRECTION: Direction;
+
+    // 前向きなら入口(ノード0)の入力、後ろ向きなら後続のないノードの出力
+    fn boundary(&self) -> Self::Domain;
+
+    // 合流の単位元。集合の和で合流するなら空集合、積で合流するなら全体集合
+    fn initial(&self) -> Self::Domain;
+
+    // accにotherを合流させる
+    fn meet(&self, acc: &mut Self::Domain, other: &Self::Domain);
+
+    // ノードの入力から出力を計算する。後ろ向きの解析では、入力はノードの後の値になる
+    fn transfer(&self, index: usize, node: &T, input: &Self::Domain) -> Self::Domain;
+}
+
+// 各ノードの直前と直後の値。解析の方向によらず、プログラムの順序での前後を表す
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataflowResult<D> {
+    pub ins: Vec<D>,
+    pub outs: Vec<D>,
+}
+
+// 不動点になるまで、逆後順に並べたワークリストで値を更新する
+pub fn solve<T, A: Analysis<T>>(
+    graph: &DirectedGraph<T>,
+    analysis: &A,
+) -> DataflowResult<A::Domain> {
+    let len = graph.len();
+    let forward = A::DIRECTION == Direction::Forward;
+
+    // 入口から到達できないノードも解析するので、順序の最後に加える
+    let mut order = graph.reverse_post_order(0);
+    let mut visited = vec![false; len];
+    for node in &order {
+        visited[*node] = true;
+    }
+    order.extend((0..len).filter(|node| !visited[*node]));
+    if !forward {
+        order.reverse();
+    }
+
+    // ノードからワークリストでの優先度への対応
+    let mut priority = vec![0; len];
+    for (i, node) in order.iter().enumerate() {
+        priority[*node] = i;
+    }
+
+    // 解析の方向での入力と出力
+    let mut inputs = vec![analysis.initial(); len];
+    let mut outputs = vec![analysis.initial(); len];
+
+    let mut worklist: BTreeSet<usize> = (0..len).collect();
+    while let Some(first) = worklist.iter().next().copied() {
+        worklist.remove(&first);
+        let node = order[first];
+
+        let mut input = analysis.initial();
+        if forward {
+            if node == 0 {
+                analysis.meet(&mut input, &analysis.boundary());
+            }
+            for pred in graph.pred_indexes(node) {
+                analysis.meet(&mut input, &outputs[pred]);
+            }
+        } else {
+            let mut has_succ = false;
+            for succ in graph.succ_indexes(node) {
+                analysis.meet(&mut input, &outputs[succ]);
+                has_succ = true;
+            }
+            if !has_succ {
+                analysis.meet(&mut input, &analysis.boundary());
+            }
+        }
+
+        let output = analysis.transfer(node, &graph[node], &input);
+        inputs[node] = input;
+        if output == outputs[node] {
+            continue;
+        }
+        outputs[node] = output;
+
+        // 出力が変わったので、それを入力にするノードを更新する
+        if forward {
+            worklist.extend(graph.succ_indexes(node).map(|succ| priority[succ]));
+        } else {
+            worklist.extend(graph.pred_indexes(node).map(|pred| priority[pred]));
+        }
+    }
+
+    if forward {
+        DataflowResult {
+            ins: inputs,
+            outs: outputs,
+        }
+    } else {
+        DataflowResult {
+            ins: outputs,
+            outs: inputs,
+        }
+    }
+}
+
+// 到達定義。値はそこに到達する定義の番号の集合
+// 集合の大きさを文の数ではなくStoreの数にするため、Storeに0から番号を付ける
+pub struct ReachingDefinitions {
+    // 変数ごとの定義の集合
+    pub defs: HashMap<Var, BitSet>,
+    // 定義の番号からStoreの位置への対応
+    pub def_stmts: Vec<usize>,
+    // 文の位置から定義の番号への対応
+    def_numbers: Vec<Option<usize>>,
+    // 入口での定義の番号の集合。入口に到達する定義になる
+    entry: BitSet,
+}
+
+impl ReachingDefinitions {
+    // StoreMemは変数の定義として扱わない
+    // グローバル変数は前回の実行や呼び出した関数で書き換えられうるので、定義を追跡しない
+    pub fn new(code: &DirectedGraph<Stmt>) -> Self {
+        let mut defs: HashMap<Var, BitSet> = HashMap::new();
+        let mut def_stmts = Vec::new();
+        let mut def_numbers = vec![None; code.len()];
+        for (i, stmt) in code.iter().enumerate() {
+            if let Stmt::Store(var, _) = stmt {
+                if !var.is_global() {
+                    defs.entry(*var).or_default().insert(def_stmts.len());
+                    def_numbers[i] = Some(def_stmts.len());
+                    def_stmts.push(i);
+                }
+            }
+        }
+
+        Self {
+            defs,
+            def_stmts,
+            def_numbers,
+            entry: BitSet::new(),
+        }
+    }
+
+    // 入口での値(引数や代入されていない変数)も定義として扱う到達定義
+    // 読まれる変数ごとに、Storeの番号の後ろに入口での定義の番号を付ける
+    pub fn with_entry_definitions(code: &DirectedGraph<Stmt>) -> Self {
+        let mut analysis = Self::new(code);
+        let vars: BTreeSet<Var> = code
+            .iter()
+            .flat_map(|stmt| stmt.used_vars())
+            .filter(|var| !var.is_global())
+            .collect();
+        for var in vars {
+            let number = analysis.def_stmts.len() + analysis.entry.len();
+            analysis.defs.entry(var).or_default().insert(number);
+            analysis.entry.insert(number);
+        }
+
+        analysis
+    }
+
+    // 入口での定義かどうか。Storeに対応する位置はない
+    pub fn is_entry_definition(&self, def: usize) -> bool {
+        self.entry.contains(def)
+    }
+}
+
+impl Analysis<Stmt> for ReachingDefinitions {
+    type Domain = BitSet;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Domain {
+        self.entry.clone()
+    }
+
+    fn initial(&self) -> Self::Domain {
+        BitSet::new()
+    }
+
+    fn meet(&self, acc: &mut Self::Domain, other: &Self::Domain) {
+        acc.union_with(other);
+    }
+
+    // out = gen U (in - kill)
+    fn transfer(&self, index: usize, stmt: &Stmt, input: &Self::Domain) -> Self::Domain {
+        let mut output = input.clone();
+        match stmt {
+            Stmt::Store(var, _) if !var.is_global() => {
+                output.difference_with(&self.defs[var]);
+                output.insert(self.def_numbers[index].unwrap());
+            }
+            // スコープの終わりでは、その変数の定義が全て無効になる
+            Stmt::EndScope(vars) => {
+                for defs in vars.iter().filter_map(|var| self.defs.get(var)) {
+                    output.difference_with(defs);
+                }
+            }
+            _ => {}
+        }
+        output
+    }
+}
+
+// 利用可能な複写。値はどの経路でも実行され、まだ無効になっていないy <- xの番号の集合
+// 複写先か複写元への代入と、どちらかのスコープの終わりで無効になる
+// グローバル変数は呼び出した関数で書き換えられうるので、複写に含めない
+pub struct AvailableCopies {
+    // 複写の番号から複写先と複写元への対応
+    pub copies: Vec<(Var, Var)>,
+    // 文の位置から複写の番号への対応
+    copy_numbers: Vec<Option<usize>>,
+    // 変数ごとの、その変数を含む複写の集合
+    kills: HashMap<Var, BitSet>,
+}
+
+impl AvailableCopies {
+    pub fn new(code: &DirectedGraph<Stmt>) -> Self {
+        let mut copies = Vec::new();
+        let mut copy_numbers = vec![None; code.len()];
+        let mut kills: HashMap<Var, BitSet> = HashMap::new();
+        for (i, stmt) in code.iter().enumerate() {
+            if let Stmt::Store(dest, Expr::LoadCopy(src)) = stmt {
+                if dest != src && !dest.is_global() && !src.is_global() {
+                    kills.entry(*dest).or_default().insert(copies.len());
+                    kills.entry(*src).or_default().insert(copies.len());
+                    copy_numbers[i] = Some(copies.len());
+                    copies.push((*dest, *src));
+                }
+            }
+        }
+
+        Self {
+            copies,
+            copy_numbers,
+            kills,
+        }
+    }
+
+    // inputで利用可能な複写のうち、varへの複写の複写元
+    pub fn source(&self, input: &BitSet, var: Var) -> Option<Var> {
+        input
+            .iter()
+            .map(|copy| self.copies[copy])
+            .find(|(dest, _)| *dest == var)
+            .map(|(_, src)| src)
+    }
+
+    fn kill(&self, output: &mut BitSet, var: &Var) {
+        if let Some(kills) = self.kills.get(var) {
+            output.difference_with(kills);
+        }
+    }
+}
+
+impl Analysis<Stmt> for AvailableCopies {
+    type Domain = BitSet;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self) -> Self::Domain {
+        BitSet::new()
+    }
+
+    fn initial(&self) -> Self::Domain {
+        (0..self.copies.len()).collect()
+    }
+
+    fn meet(&self, acc: &mut Self::Domain, other: &Self::Domain) {
+        acc.intersect_with(other);
+    }
+
+    // out = gen U (in - kill)
+    fn transfer(&self, index: usize, stmt: &Stmt, input: &Self::Domain) -> Self::Domain {
+        let mut output = input.clone();
+        match stmt {
+            Stmt::Store(var, _) | Stmt::Phi(var, _) => self.kill(&mut output, var),
+            Stmt::EndScope(vars) => {
+                for var in vars {
+                    self.kill(&mut output, var);
+                }
+            }
+            _ => {}
+        }
+        if let Some(copy) = self.copy_numbers[index] {
+            output.insert(copy);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::code_to_graph;
+    use crate::ir::{Expr::*, Label};
+
+    #[test]
+    fn test_reaching_definitions() {
+        let x = Var::new("x");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // 0: x <- 1
+        // 1: L0:
+        // 2: jump_if_zero x -> L1
+        // 3: x <- 2
+        // 4: jump L0
+        // 5: L1:
+        // 6: print x
+        let code = code_to_graph(vec![
+            Stmt::Store(x, Int(1)),
+            Stmt::Label(l0),
+            Stmt::JumpIfZero(LoadCopy(x), l1),
+            Stmt::Store(x, Int(2)),
+            Stmt::Jump(l0),
+            Stmt::Label(l1),
+            Stmt::Print(LoadCopy(x)),
+        ]);
+
+        let analysis = ReachingDefinitions::new(&code);
+        let result = solve(&code, &analysis);
+        let stmts = |defs: &BitSet| -> Vec<usize> {
+            defs.iter().map(|def| analysis.def_stmts[def]).collect()
+        };
+        assert_eq!(stmts(&result.ins[0]), vec![]);
+        assert_eq!(stmts(&result.ins[1]), vec![0, 3]);
+        assert_eq!(stmts(&result.outs[3]), vec![3]);
+        assert_eq!(stmts(&result.ins[6]), vec![0, 3]);
+    }
+
+    // 後ろ向きの解析の例として、その後で出力されるかどうかを求める
+    struct PrintsLater;
+
+    impl Analysis<Stmt> for PrintsLater {
+        type Domain = bool;
+
+        const DIRECTION: Direction = Direction::Backward;
+
+        fn boundary(&self) -> bool {
+            false
+        }
+
+        fn initial(&self) -> bool {
+            false
+        }
+
+        fn meet(&self, acc: &mut bool, other: &bool) {
+            *acc |= *other;
+        }
+
+        fn transfer(&self, _: usize, stmt: &Stmt, input: &bool) -> bool {
+            *input || matches!(stmt, Stmt::Print(_))
+        }
+    }
+
+    #[test]
+    fn test_entry_definitions() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let l0 = Label::new();
+        let code = code_to_graph(vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Store(x, Int(1)),
+            Stmt::Label(l0),
+            Stmt::Print(Add(Box::new(LoadCopy(x)), Box::new(LoadCopy(y)))),
+        ]);
+
+        // 定義の番号はStoreの0の後ろに、読まれる変数ごとに付く
+        let analysis = ReachingDefinitions::with_entry_definitions(&code);
+        let result = solve(&code, &analysis);
+        assert_eq!(result.ins[0].iter().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(result.ins[3].iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(!analysis.is_entry_definition(0));
+        assert!(analysis.is_entry_definition(1));
+        assert_eq!(analysis.defs[&x].iter().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(analysis.defs[&y].iter().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_backward() {
+        let l0 = Label::new();
+        let code = code_to_graph(vec![
+            Stmt::JumpIfZero(Int(0), l0),
+            Stmt::Print(Int(1)),
+            Stmt::Label(l0),
+            Stmt::Return(Int(0)),
+        ]);
+
+        let result = solve(&code, &PrintsLater);
+        assert_eq!(result.ins, vec![true, true, false, false]);
+        assert_eq!(result.outs, vec![true, false, false, false]);
+    }
+}
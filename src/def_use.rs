@@ -0,0 +1,146 @@
+use crate::code_to_graph;
+use crate::dataflow::{self, ReachingDefinitions};
+use crate::ir::{Stmt, Var};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// 定義と使用の連鎖
+// 文の位置で、変数を読む文とそこに到達するStoreを対応付ける
+// lower_control_flow()で変換した後の、SSA形式でないコードを対象にする
+// グローバル変数は定義を追跡しないので、到達するStoreは常に空になる
+pub struct DefUse {
+    // 文と変数ごとの、到達するStoreの位置
+    stores: HashMap<(usize, Var), Vec<usize>>,
+    // 入口での値が到達する、文と変数
+    from_entry: HashSet<(usize, Var)>,
+    // Storeの位置ごとの、その値を読む文の位置
+    uses: HashMap<usize, Vec<usize>>,
+}
+
+impl DefUse {
+    pub fn new(code: &[Stmt]) -> Self {
+        let graph = code_to_graph(code.to_vec());
+        let analysis = ReachingDefinitions::with_entry_definitions(&graph);
+        let result = dataflow::solve(&graph, &analysis);
+
+        let mut stores = HashMap::new();
+        let mut from_entry = HashSet::new();
+        let mut uses: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (i, stmt) in graph.iter().enumerate() {
+            let used: BTreeSet<Var> = stmt.used_vars().into_iter().collect();
+            for var in used {
+                let reached = match analysis.defs.get(&var) {
+                    Some(defs) => defs & &result.ins[i],
+                    None => continue,
+                };
+
+                let mut reaching_stores = Vec::new();
+                for def in reached.iter() {
+                    if analysis.is_entry_definition(def) {
+                        from_entry.insert((i, var));
+                    } else {
+                        let store = analysis.def_stmts[def];
+                        reaching_stores.push(store);
+                        uses.entry(store).or_default().push(i);
+                    }
+                }
+                stores.insert((i, var), reaching_stores);
+            }
+        }
+
+        Self {
+            stores,
+            from_entry,
+            uses,
+        }
+    }
+
+    // index番目の文が読むvarの値を代入した可能性のあるStoreの位置
+    pub fn reaching_stores(&self, index: usize, var: Var) -> &[usize] {
+        self.stores
+            .get(&(index, var))
+            .map_or(&[], |stores| stores.as_slice())
+    }
+
+    // index番目の文が読むvarに、入口での値が届く可能性があるかどうか
+    pub fn reaches_from_entry(&self, index: usize, var: Var) -> bool {
+        self.from_entry.contains(&(index, var))
+    }
+
+    // store番目の文で代入した値を読む可能性のある文の位置
+    pub fn uses(&self, store: usize) -> &[usize] {
+        self.uses.get(&store).map_or(&[], |uses| uses.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Expr::*, Label};
+
+    #[test]
+    fn test_def_use() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // 0: x <- 1
+        // 1: L0:
+        // 2: jump_if_zero x -> L1
+        // 3: y <- x + x
+        // 4: x <- y
+        // 5: jump L0
+        // 6: L1:
+        // 7: print x
+        let code = vec![
+            Stmt::Store(x, Int(1)),
+            Stmt::Label(l0),
+            Stmt::JumpIfZero(LoadCopy(x), l1),
+            Stmt::Store(y, Add(Box::new(LoadCopy(x)), Box::new(LoadCopy(x)))),
+            Stmt::Store(x, LoadCopy(y)),
+            Stmt::Jump(l0),
+            Stmt::Label(l1),
+            Stmt::Print(LoadCopy(x)),
+        ];
+
+        let def_use = DefUse::new(&code);
+        let mut reaching = def_use.reaching_stores(7, x).to_vec();
+        reaching.sort_unstable();
+        assert_eq!(reaching, vec![0, 4]);
+        assert_eq!(def_use.reaching_stores(4, y), &[3]);
+        assert_eq!(def_use.reaching_stores(7, y), &[] as &[usize]);
+        assert!(!def_use.reaches_from_entry(7, x));
+
+        // 同じ文で二回読んでも一度だけ数える
+        let mut uses = def_use.uses(0).to_vec();
+        uses.sort_unstable();
+        assert_eq!(uses, vec![2, 3, 7]);
+        assert_eq!(def_use.uses(3), &[4]);
+        assert_eq!(def_use.uses(7), &[] as &[usize]);
+    }
+
+    #[test]
+    fn test_reaches_from_entry() {
+        let x = Var::new("x");
+        let g = Var::global("def_use");
+        let l0 = Label::new();
+        let code = vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Store(x, Int(1)),
+            Stmt::Label(l0),
+            Stmt::Print(LoadCopy(x)),
+            Stmt::Store(g, Int(2)),
+            Stmt::Print(LoadCopy(g)),
+            Stmt::EndScope(vec![x]),
+            Stmt::Print(LoadCopy(x)),
+        ];
+
+        let def_use = DefUse::new(&code);
+        assert_eq!(def_use.reaching_stores(3, x), &[1]);
+        assert!(def_use.reaches_from_entry(3, x));
+
+        // グローバル変数とスコープの外の変数には、到達する定義がない
+        assert_eq!(def_use.reaching_stores(5, g), &[] as &[usize]);
+        assert_eq!(def_use.reaching_stores(7, x), &[] as &[usize]);
+        assert!(!def_use.reaches_from_entry(7, x));
+    }
+}
@@ -0,0 +1,406 @@
+use crate::bitset::BitSet;
+use crate::graph::DirectedGraph;
+
+// 支配木と支配辺境
+// 入口から到達できないノードは、どのノードにも支配されず、どのノードも支配しない
+pub struct Dominators {
+    root: usize,
+    // 直接支配ノード。入口と到達できないノードはNone
+    idom: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    // 支配木での行きがけ順と帰りがけ順の番号。支配関係を定数時間で調べるのに使う
+    pre: Vec<usize>,
+    post: Vec<usize>,
+    reachable: Vec<bool>,
+    frontiers: Vec<BitSet>,
+}
+
+impl Dominators {
+    // Cooper, Harvey, Kennedyの反復アルゴリズムで直接支配ノードを求める
+    pub fn new<T>(graph: &DirectedGraph<T>, root: usize) -> Self {
+        let len = graph.len();
+        let order = graph.reverse_post_order(root);
+
+        let mut reachable = vec![false; len];
+        let mut rpo_number = vec![0; len];
+        for (i, node) in order.iter().enumerate() {
+            reachable[*node] = true;
+            rpo_number[*node] = i;
+        }
+
+        // 計算中は入口の直接支配ノードを入口自身にしておく
+        let mut idom: Vec<Option<usize>> = vec![None; len];
+        if root < len {
+            idom[root] = Some(root);
+        }
+
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rpo_number[a] > rpo_number[b] {
+                    a = idom[a].unwrap();
+                }
+                while rpo_number[b] > rpo_number[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for node in order.iter().skip(1).copied() {
+                let mut new_idom = None;
+                for pred in graph.pred_indexes(node) {
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        Some(new_idom) => intersect(&idom, pred, new_idom),
+                        None => pred,
+                    });
+                }
+
+                if new_idom != idom[node] {
+                    idom[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        if root < len {
+            idom[root] = None;
+        }
+
+        let mut children = vec![Vec::new(); len];
+        for node in &order {
+            if let Some(parent) = idom[*node] {
+                children[parent].push(*node);
+            }
+        }
+
+        // 支配木を再帰を使わずに辿って番号を付ける
+        let mut pre = vec![0; len];
+        let mut post = vec![0; len];
+        let mut counter = 0;
+        let mut stack: Vec<(usize, usize)> = Vec::new();
+        if root < len {
+            pre[root] = counter;
+            counter += 1;
+            stack.push((root, 0));
+        }
+        while let Some((node, next)) = stack.last_mut() {
+            match children[*node].get(*next) {
+                Some(child) => {
+                    *next += 1;
+                    pre[*child] = counter;
+                    counter += 1;
+                    stack.push((*child, 0));
+                }
+                None => {
+                    post[*node] = counter;
+                    counter += 1;
+                    stack.pop();
+                }
+            }
+        }
+
+        // 合流点の先行ノードから直接支配ノードまで遡り、途中のノードの支配辺境に合流点を加える
+        let mut frontiers = vec![BitSet::new(); len];
+        for node in order.iter().copied() {
+            let preds: Vec<usize> = graph
+                .pred_indexes(node)
+                .filter(|pred| reachable[*pred])
+                .collect();
+            // 入口には外から入る辺があるものとして扱う
+            if preds.len() + usize::from(node == root) < 2 {
+                continue;
+            }
+
+            for pred in preds {
+                let mut runner = Some(pred);
+                while let Some(r) = runner {
+                    if Some(r) == idom[node] {
+                        break;
+                    }
+                    frontiers[r].insert(node);
+                    runner = idom[r];
+                }
+            }
+        }
+
+        Self {
+            root,
+            idom,
+            children,
+            pre,
+            post,
+            reachable,
+            frontiers,
+        }
+    }
+
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    pub fn is_reachable(&self, node: usize) -> bool {
+        self.reachable[node]
+    }
+
+    pub fn immediate_dominator(&self, node: usize) -> Option<usize> {
+        self.idom[node]
+    }
+
+    // 支配木での子
+    pub fn children(&self, node: usize) -> &[usize] {
+        &self.children[node]
+    }
+
+    // aがbを支配するかどうか。全てのノードは自身を支配する
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        self.reachable[a]
+            && self.reachable[b]
+            && self.pre[a] <= self.pre[b]
+            && self.post[b] <= self.post[a]
+    }
+
+    pub fn strictly_dominates(&self, a: usize, b: usize) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    // nodeの支配辺境
+    pub fn frontier(&self, node: usize) -> &BitSet {
+        &self.frontiers[node]
+    }
+}
+
+// 後支配木
+// 出口が複数ある場合や出口のない無限ループがある場合にも一つの木になるように、仮想的な出口ノードを加える
+// 後続のない文と、出口に辿り着けないループの中の文から仮想的な出口に辺を張る
+// ループの中から張った辺は実際には辿られないので、pseudo_exits()で別に返す
+pub struct PostDominators {
+    exit: usize,
+    pseudo_exits: Vec<usize>,
+    dominators: Dominators,
+}
+
+impl PostDominators {
+    pub fn new<T>(graph: &DirectedGraph<T>) -> Self {
+        let len = graph.len();
+        let exit = len;
+
+        // 辺を逆にしたグラフ。仮想的な出口は最後のノード
+        let mut reverse = DirectedGraph::with_capacity(len + 1);
+        for _ in 0..=len {
+            reverse.add(());
+        }
+        for (from, to) in graph.edges() {
+            for to in to {
+                reverse.add_edge(to, from);
+            }
+        }
+        for node in 0..len {
+            if graph.succ_indexes(node).next().is_none() {
+                reverse.add_edge(exit, node);
+            }
+        }
+
+        // 出口に辿り着けないノードがなくなるまで、そこから出口に辺を張る
+        // 後順で最初に現れるノードはループの後ろへの辺の元になりやすいので、後順で選ぶ
+        let mut reached = vec![false; len + 1];
+        for node in reverse.reverse_post_order(exit) {
+            reached[node] = true;
+        }
+        let mut candidates = graph.reverse_post_order(0);
+        candidates.reverse();
+        candidates.extend(0..len);
+        let mut pseudo_exits = Vec::new();
+        for node in candidates {
+            if reached[node] {
+                continue;
+            }
+            reverse.add_edge(exit, node);
+            pseudo_exits.push(node);
+            for node in reverse.reverse_post_order(node) {
+                reached[node] = true;
+            }
+        }
+
+        pseudo_exits.sort_unstable();
+        Self {
+            exit,
+            pseudo_exits,
+            dominators: Dominators::new(&reverse, exit),
+        }
+    }
+
+    // 仮想的な出口ノードの番号。元のグラフのノード数と同じ
+    pub fn exit(&self) -> usize {
+        self.exit
+    }
+
+    // 出口に辿り着けないために、仮想的な出口への辺を張ったノード
+    // これらのノードは後支配辺境に仮想的な出口への辺の分岐として現れることがある
+    pub fn pseudo_exits(&self) -> &[usize] {
+        &self.pseudo_exits
+    }
+
+    // 直接後支配ノード。仮想的な出口のこともある
+    pub fn immediate_post_dominator(&self, node: usize) -> Option<usize> {
+        self.dominators.immediate_dominator(node)
+    }
+
+    // aがbを後支配するかどうか。全てのノードは自身を後支配する
+    pub fn post_dominates(&self, a: usize, b: usize) -> bool {
+        self.dominators.dominates(a, b)
+    }
+
+    pub fn strictly_post_dominates(&self, a: usize, b: usize) -> bool {
+        self.dominators.strictly_dominates(a, b)
+    }
+
+    // 後支配辺境。nodeが制御依存する分岐の集合になる
+    pub fn frontier(&self, node: usize) -> &BitSet {
+        self.dominators.frontier(node)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{stmts_to_bbs, Expr::*, Label, Stmt, Var};
+    use crate::{bbs_to_graph, code_to_graph};
+
+    #[test]
+    fn test_dominators() {
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let l2 = Label::new();
+        // 0: L0:
+        // 1: jump_if_zero read -> L1
+        // 2: print 1
+        // 3: jump L2
+        // 4: L1:
+        // 5: print 2
+        // 6: L2:
+        // 7: jump_if_zero read -> L0
+        // 8: return 0
+        // 9: print 3
+        let code = code_to_graph(vec![
+            Stmt::Label(l0),
+            Stmt::JumpIfZero(Read, l1),
+            Stmt::Print(Int(1)),
+            Stmt::Jump(l2),
+            Stmt::Label(l1),
+            Stmt::Print(Int(2)),
+            Stmt::Label(l2),
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Return(Int(0)),
+            Stmt::Print(Int(3)),
+        ]);
+
+        let dom = Dominators::new(&code, 0);
+        assert_eq!(dom.immediate_dominator(0), None);
+        assert_eq!(dom.immediate_dominator(2), Some(1));
+        assert_eq!(dom.immediate_dominator(4), Some(1));
+        assert_eq!(dom.immediate_dominator(6), Some(1));
+        assert_eq!(dom.immediate_dominator(8), Some(7));
+        assert!(dom.dominates(1, 8));
+        assert!(dom.dominates(6, 6));
+        assert!(!dom.strictly_dominates(6, 6));
+        assert!(!dom.dominates(2, 6));
+
+        // 到達できない文
+        assert!(!dom.is_reachable(9));
+        assert!(!dom.dominates(0, 9));
+        assert_eq!(dom.immediate_dominator(9), None);
+
+        let frontier = |node| dom.frontier(node).iter().collect::<Vec<_>>();
+        assert_eq!(frontier(3), vec![6]);
+        assert_eq!(frontier(5), vec![6]);
+        assert_eq!(frontier(6), vec![0]);
+        assert_eq!(frontier(0), vec![0]);
+        assert_eq!(frontier(8), vec![]);
+    }
+
+    #[test]
+    fn test_basic_block_dominators() {
+        let x = Var::new("x");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // B0: x <- read; jump L0
+        // B1: L0: jump_if_zero x -> L1
+        // B2: x <- x - 1; jump L0
+        // B3: L1: print x
+        let bbs = stmts_to_bbs(vec![
+            Stmt::Store(x, Read),
+            Stmt::Label(l0),
+            Stmt::JumpIfZero(LoadCopy(x), l1),
+            Stmt::Store(x, Sub(Box::new(LoadCopy(x)), Box::new(Int(1)))),
+            Stmt::Jump(l0),
+            Stmt::Label(l1),
+            Stmt::Print(LoadCopy(x)),
+        ]);
+        let graph = bbs_to_graph(bbs);
+        assert_eq!(graph.len(), 4);
+
+        let dom = Dominators::new(&graph, 0);
+        assert_eq!(dom.immediate_dominator(1), Some(0));
+        assert_eq!(dom.immediate_dominator(2), Some(1));
+        assert_eq!(dom.immediate_dominator(3), Some(1));
+        assert_eq!(dom.children(1).len(), 2);
+        assert!(dom.dominates(1, 2));
+        assert_eq!(dom.frontier(2).iter().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(dom.frontier(1).iter().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_post_dominators() {
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let l2 = Label::new();
+        // 0: jump_if_zero read -> L0
+        // 1: return 1
+        // 2: L0:
+        // 3: jump_if_zero read -> L2
+        // 4: L1:
+        // 5: print 2
+        // 6: jump L1
+        // 7: L2:
+        // 8: return 3
+        let code = code_to_graph(vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Return(Int(1)),
+            Stmt::Label(l0),
+            Stmt::JumpIfZero(Read, l2),
+            Stmt::Label(l1),
+            Stmt::Print(Int(2)),
+            Stmt::Jump(l1),
+            Stmt::Label(l2),
+            Stmt::Return(Int(3)),
+        ]);
+
+        let pdom = PostDominators::new(&code);
+        let exit = pdom.exit();
+        assert_eq!(exit, 9);
+        assert_eq!(pdom.immediate_post_dominator(exit), None);
+        assert_eq!(pdom.immediate_post_dominator(0), Some(exit));
+        assert_eq!(pdom.immediate_post_dominator(2), Some(3));
+        assert_eq!(pdom.immediate_post_dominator(3), Some(exit));
+        assert_eq!(pdom.immediate_post_dominator(7), Some(8));
+        assert!(pdom.post_dominates(8, 7));
+        assert!(!pdom.post_dominates(8, 3));
+
+        // 無限ループの中の文も後支配木に含まれる
+        assert!(pdom.post_dominates(exit, 5));
+        assert!(pdom.strictly_post_dominates(5, 4));
+
+        let frontier = |node| pdom.frontier(node).iter().collect::<Vec<_>>();
+        assert_eq!(frontier(1), vec![0]);
+        assert_eq!(frontier(3), vec![0]);
+        assert_eq!(frontier(8), vec![3]);
+        assert_eq!(pdom.pseudo_exits(), &[6]);
+        assert_eq!(frontier(5), vec![3, 6]);
+    }
+}
@@ -87,6 +87,38 @@ impl<T> DirectedGraph<T> {
         self.pred[index].iter().copied()
     }
 
+    // startから到達できるノードの逆後順
+    // 深いグラフでもスタックが溢れないように、再帰を使わずに辿る
+    pub fn reverse_post_order(&self, start: usize) -> Vec<usize> {
+        let mut visited = vec![false; self.len()];
+        let mut order = Vec::with_capacity(self.len());
+        // ノードと、まだ辿っていない後続
+        let mut stack: Vec<(usize, Vec<usize>)> = Vec::new();
+
+        if start < self.len() {
+            visited[start] = true;
+            stack.push((start, self.succ_indexes(start).collect()));
+        }
+
+        while let Some((node, succ)) = stack.last_mut() {
+            match succ.pop() {
+                Some(next) if !visited[next] => {
+                    visited[next] = true;
+                    let next_succ = self.succ_indexes(next).collect();
+                    stack.push((next, next_succ));
+                }
+                Some(_) => {}
+                None => {
+                    order.push(*node);
+                    stack.pop();
+                }
+            }
+        }
+
+        order.reverse();
+        order
+    }
+
     pub fn get(&self, index: usize) -> Option<&T> {
         self.nodes.get(index)
     }
@@ -190,4 +222,25 @@ mod test {
         assert!(indexes.contains(&b));
         assert!(indexes.contains(&c));
     }
+
+    #[test]
+    fn test_reverse_post_order() {
+        let mut graph = DirectedGraph::new();
+        let a = graph.add(0);
+        let b = graph.add(1);
+        let c = graph.add(2);
+        let d = graph.add(3);
+        let unreachable = graph.add(4);
+        graph.add_edge(a, b);
+        graph.add_edge(a, c);
+        graph.add_edge(b, d);
+        graph.add_edge(c, d);
+        graph.add_edge(d, a);
+        graph.add_edge(unreachable, d);
+
+        let order = graph.reverse_post_order(a);
+        assert_eq!(order.len(), 4);
+        assert_eq!(order[0], a);
+        assert_eq!(order[3], d);
+    }
 }
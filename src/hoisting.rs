@@ -0,0 +1,289 @@
+use crate::bitset::BitSet;
+use crate::code_to_graph;
+use crate::cse::{same_expr, ExprTable};
+use crate::dataflow::{self, Analysis, Direction};
+use crate::graph::DirectedGraph;
+use crate::ir::{Expr, Stmt, Var};
+use std::collections::{HashMap, HashSet};
+
+// 超過敏式(very busy expressions)解析
+// 値はその後の全ての経路で、変数が書き換えられる前に必ず計算される式の集合
+// lower_control_flow()で変換した後のコードを対象にする
+pub struct VeryBusyExpressions {
+    table: ExprTable,
+    uses: Vec<BitSet>,
+    kills: Vec<BitSet>,
+}
+
+impl VeryBusyExpressions {
+    pub fn new(code: &DirectedGraph<Stmt>) -> Self {
+        let table = ExprTable::new(code.iter());
+
+        let mut uses = Vec::with_capacity(code.len());
+        let mut kills = Vec::with_capacity(code.len());
+        for stmt in code.iter() {
+            let mut used = BitSet::new();
+            for expr in stmt.exprs() {
+                table.computed(expr, &mut used);
+            }
+
+            uses.push(used);
+            kills.push(table.killed(stmt));
+        }
+
+        Self { table, uses, kills }
+    }
+}
+
+impl Analysis<Stmt> for VeryBusyExpressions {
+    type Domain = BitSet;
+
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn boundary(&self) -> Self::Domain {
+        BitSet::new()
+    }
+
+    fn initial(&self) -> Self::Domain {
+        (0..self.table.len()).collect()
+    }
+
+    fn meet(&self, acc: &mut Self::Domain, other: &Self::Domain) {
+        acc.intersect_with(other);
+    }
+
+    // 文の式は代入より先に計算されるので、in = use U (out - kill)
+    fn transfer(&self, index: usize, _: &Stmt, output: &Self::Domain) -> Self::Domain {
+        let mut input = output.clone();
+        input.difference_with(&self.kills[index]);
+        input.union_with(&self.uses[index]);
+        input
+    }
+}
+
+// 式の命令数
+fn inst_count(expr: &Expr) -> usize {
+    1 + expr.subexprs().into_iter().map(inst_count).sum::<usize>()
+}
+
+fn count_occurrences(expr: &Expr, target: &Expr) -> usize {
+    if same_expr(expr, target) {
+        return 1;
+    }
+    expr.subexprs()
+        .into_iter()
+        .map(|expr| count_occurrences(expr, target))
+        .sum()
+}
+
+fn replace(expr: &mut Expr, target: &Expr, temp: Var) {
+    if same_expr(expr, target) {
+        *expr = Expr::LoadCopy(temp);
+        return;
+    }
+    for expr in expr.subexprs_mut() {
+        replace(expr, target, temp);
+    }
+}
+
+// 分岐から式が最初に計算される文までの範囲
+struct Region {
+    // 範囲に含まれる文。式を計算する文も含む
+    stmts: HashSet<usize>,
+    // 式を計算する文
+    occurrences: Vec<usize>,
+}
+
+// branchの後続から、式を計算する文まで辿る
+// 範囲の途中に外から合流する経路があると、一時変数に値が入っていないことがあるので諦める
+fn find_region(
+    graph: &DirectedGraph<Stmt>,
+    analysis: &VeryBusyExpressions,
+    branch: usize,
+    index: usize,
+) -> Option<Region> {
+    let mut region = Region {
+        stmts: HashSet::new(),
+        occurrences: Vec::new(),
+    };
+    let mut stack: Vec<usize> = graph.succ_indexes(branch).collect();
+    while let Some(stmt) = stack.pop() {
+        if stmt == branch {
+            return None;
+        }
+        if !region.stmts.insert(stmt) {
+            continue;
+        }
+
+        if analysis.uses[stmt].contains(index) {
+            region.occurrences.push(stmt);
+            continue;
+        }
+
+        let mut succ = graph.succ_indexes(stmt).peekable();
+        succ.peek()?;
+        stack.extend(succ);
+    }
+
+    let closed = region.stmts.iter().all(|stmt| {
+        graph
+            .pred_indexes(*stmt)
+            .all(|pred| pred == branch || region.stmts.contains(&pred))
+    });
+    if closed {
+        Some(region)
+    } else {
+        None
+    }
+}
+
+// 分岐の後の全ての経路で計算される式を、分岐の前で一度だけ計算する
+// 命令数が減る場合だけ移動する
+pub fn code_hoisting(code: Vec<Stmt>) -> Vec<Stmt> {
+    let mut code = code;
+
+    // 移動した式の一部がさらに移動できることがあるので、変わらなくなるまで繰り返す
+    loop {
+        let mut graph = code_to_graph(code);
+        let analysis = VeryBusyExpressions::new(&graph);
+        let result = dataflow::solve(&graph, &analysis);
+
+        // 分岐の位置と、その前に入れる文
+        let mut hoisted: HashMap<usize, Vec<Stmt>> = HashMap::new();
+        // 一度の繰り返しで同じ文を二回書き換えないようにする
+        let mut touched = HashSet::new();
+
+        for branch in 0..graph.len() {
+            if !matches!(graph[branch], Stmt::JumpIfZero(_, _)) {
+                continue;
+            }
+
+            for index in result.outs[branch].iter() {
+                let region = match find_region(&graph, &analysis, branch, index) {
+                    Some(region) => region,
+                    None => continue,
+                };
+                if region.stmts.iter().any(|stmt| touched.contains(stmt)) {
+                    continue;
+                }
+
+                let expr = analysis.table.expr(index).clone();
+                let occurrences: usize = region
+                    .occurrences
+                    .iter()
+                    .flat_map(|stmt| graph[*stmt].exprs())
+                    .map(|e| count_occurrences(e, &expr))
+                    .sum();
+
+                // n回計算していた式を、一回の計算と一時変数へのStoreとn回のLoadCopyに置き換える
+                let size = inst_count(&expr);
+                if occurrences * size <= size + 1 + occurrences {
+                    continue;
+                }
+
+                let temp = Var::new("hoist");
+                for stmt in &region.occurrences {
+                    for e in graph[*stmt].exprs_mut() {
+                        replace(e, &expr, temp);
+                    }
+                }
+                touched.extend(region.stmts);
+                touched.insert(branch);
+                hoisted
+                    .entry(branch)
+                    .or_default()
+                    .push(Stmt::Store(temp, expr));
+                break;
+            }
+        }
+
+        if hoisted.is_empty() {
+            return graph.into_iter().collect();
+        }
+
+        code = Vec::with_capacity(graph.len() + hoisted.len());
+        for (i, stmt) in graph.into_iter().enumerate() {
+            if let Some(stores) = hoisted.remove(&i) {
+                code.extend(stores);
+            }
+            code.push(stmt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Expr::*, Label};
+    use crate::ir_to_insts;
+
+    // a * b + c * d
+    fn expr(a: Var, b: Var, c: Var, d: Var) -> Expr {
+        Add(
+            Box::new(Mul(Box::new(LoadCopy(a)), Box::new(LoadCopy(b)))),
+            Box::new(Mul(Box::new(LoadCopy(c)), Box::new(LoadCopy(d)))),
+        )
+    }
+
+    #[test]
+    fn test_hoisting() {
+        let (a, b, c, d) = (Var::new("a"), Var::new("b"), Var::new("c"), Var::new("d"));
+        let (x, y) = (Var::new("x"), Var::new("y"));
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let code = vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Print(Int(1)),
+            Stmt::Store(x, expr(a, b, c, d)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Store(y, expr(a, b, c, d)),
+            Stmt::Label(l1),
+        ];
+        let before = ir_to_insts(&code).unwrap().insts.len();
+
+        let code = code_hoisting(code);
+        let temp = match &code[0] {
+            Stmt::Store(temp, e) if e == &expr(a, b, c, d) => *temp,
+            stmt => panic!("unexpected statement: {}", stmt),
+        };
+        assert_eq!(code[3], Stmt::Store(x, LoadCopy(temp)));
+        assert_eq!(code[6], Stmt::Store(y, LoadCopy(temp)));
+        assert!(ir_to_insts(&code).unwrap().insts.len() < before);
+    }
+
+    #[test]
+    fn test_not_hoisted() {
+        let (a, b, c, d) = (Var::new("a"), Var::new("b"), Var::new("c"), Var::new("d"));
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let l2 = Label::new();
+
+        // 片方の分岐で先にaを書き換える
+        let code = vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Print(expr(a, b, c, d)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Store(a, Int(1)),
+            Stmt::Print(expr(a, b, c, d)),
+            Stmt::Label(l1),
+        ];
+        assert_eq!(code_hoisting(code.clone()), code);
+
+        // 内側の分岐を通らずにl1に来る経路がある
+        // 外側の分岐の後ではaが書き換えられるので、外側にも移動できない
+        let code = vec![
+            Stmt::JumpIfZero(Read, l1),
+            Stmt::Store(a, Int(1)),
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Print(expr(a, b, c, d)),
+            Stmt::Jump(l2),
+            Stmt::Label(l0),
+            Stmt::Label(l1),
+            Stmt::Print(expr(a, b, c, d)),
+            Stmt::Label(l2),
+        ];
+        assert_eq!(code_hoisting(code.clone()), code);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::code_to_graph;
+use crate::graph::DirectedGraph;
+use crate::ir::{Expr, Stmt};
+
+// iから始まる次の形のひし形を探し、一つのStoreにまとめる
+//
+//   jump_if_zero c -> L1
+//   v <- a
+//   jump L2
+// L1:
+//   v <- b
+// L2:
+//
+// => v <- select(c, a, b)
+fn convert_diamond(graph: &DirectedGraph<Stmt>, i: usize) -> Option<Stmt> {
+    if i + 5 >= graph.len() {
+        return None;
+    }
+
+    let (cond, else_label) = match &graph[i] {
+        Stmt::JumpIfZero(cond, label) => (cond, label),
+        _ => return None,
+    };
+    let (then_var, then_expr) = match &graph[i + 1] {
+        Stmt::Store(var, expr) => (var, expr),
+        _ => return None,
+    };
+    let end_label = match &graph[i + 2] {
+        Stmt::Jump(label) => label,
+        _ => return None,
+    };
+    match &graph[i + 3] {
+        Stmt::Label(label) if label == else_label => {}
+        _ => return None,
+    }
+    let (else_var, else_expr) = match &graph[i + 4] {
+        Stmt::Store(var, expr) => (var, expr),
+        _ => return None,
+    };
+    match &graph[i + 5] {
+        Stmt::Label(label) if label == end_label => {}
+        _ => return None,
+    }
+
+    if then_var != else_var {
+        return None;
+    }
+
+    // 両方の式を評価することになるので、副作用やトラップがあってはいけない
+    if !then_expr.is_pure() || !else_expr.is_pure() {
+        return None;
+    }
+
+    // ラベルにひし形の外から飛んでくる場合は変換できない
+    let else_pred: Vec<usize> = graph.pred_indexes(i + 3).collect();
+    if else_pred != [i] {
+        return None;
+    }
+    let end_pred = graph.pred_indexes(i + 5);
+    if end_pred
+        .into_iter()
+        .any(|pred| pred != i + 2 && pred != i + 4)
+    {
+        return None;
+    }
+
+    Some(Stmt::Store(
+        *then_var,
+        Expr::Select(
+            Box::new(cond.clone()),
+            Box::new(then_expr.clone()),
+            Box::new(else_expr.clone()),
+        ),
+    ))
+}
+
+// 各分岐で一つの変数にStoreするだけの小さな分岐をselectに置き換える
+pub fn if_conversion(code: Vec<Stmt>) -> Vec<Stmt> {
+    let mut code = code;
+
+    // 内側の分岐を変換すると外側の分岐も変換できるようになることがあるので、変化がなくなるまで繰り返す
+    loop {
+        let graph = code_to_graph(code.clone());
+        let mut new_code = Vec::with_capacity(code.len());
+        let mut changed = false;
+
+        let mut i = 0;
+        while i < graph.len() {
+            if let Some(stmt) = convert_diamond(&graph, i) {
+                new_code.push(stmt);
+                changed = true;
+                i += 6;
+            } else {
+                new_code.push(graph[i].clone());
+                i += 1;
+            }
+        }
+
+        code = new_code;
+
+        if !changed {
+            return code;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Expr::*, Label, Var};
+
+    #[test]
+    fn test_diamond() {
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let c = Var::new("c");
+        let x = Var::new("x");
+        let code = vec![
+            Stmt::JumpIfZero(LoadCopy(c), l0),
+            Stmt::Store(x, Int(10)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Store(x, Int(20)),
+            Stmt::Label(l1),
+            Stmt::Print(LoadCopy(x)),
+        ];
+
+        let select = Select(Box::new(LoadCopy(c)), Box::new(Int(10)), Box::new(Int(20)));
+        assert_eq!(
+            if_conversion(code),
+            vec![Stmt::Store(x, select), Stmt::Print(LoadCopy(x))]
+        );
+    }
+
+    #[test]
+    fn test_label_used_outside() {
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let c = Var::new("c");
+        let x = Var::new("x");
+        let code = vec![
+            Stmt::JumpIfZero(LoadCopy(c), l0),
+            Stmt::Store(x, Int(10)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Store(x, Int(20)),
+            Stmt::Label(l1),
+            Stmt::JumpIfZero(LoadCopy(x), l0),
+        ];
+
+        assert_eq!(if_conversion(code.clone()), code);
+    }
+}
@@ -1,5 +1,8 @@
+use crate::FormatIter;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 pub static NEXT_LABEL: AtomicUsize = AtomicUsize::new(0);
 
@@ -16,40 +19,531 @@ impl Label {
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
+// 変数の表示名の表。Varの番号で引く
+struct SymbolTable {
+    names: Vec<String>,
+    // 番号を付ける前の名前
+    bases: Vec<String>,
+    // 名前ごとに使われた回数。同じ名前の変数は番号を付けて区別する
+    counts: BTreeMap<String, usize>,
+    // グローバル変数は名前で区別するので、同じ名前には同じ変数を返す
+    globals: BTreeMap<String, Var>,
+}
+
+static SYMBOLS: Mutex<SymbolTable> = Mutex::new(SymbolTable {
+    names: Vec::new(),
+    bases: Vec::new(),
+    counts: BTreeMap::new(),
+    globals: BTreeMap::new(),
+});
+
+// グローバル変数の表示名の先頭に付ける
+const GLOBAL_PREFIX: char = '@';
+
+// 名前付きの変数。VMの変数の位置はir_to_insts()で割り当てる
+// グローバル変数かどうかは表を引かずに分かるように持っておく
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Var {
+    index: usize,
+    global: bool,
+}
+
+impl SymbolTable {
+    fn add(&mut self, name: &str) -> Var {
+        let count = self.counts.entry(name.to_string()).or_insert(0);
+        let unique_name = match *count {
+            0 => name.to_string(),
+            n => format!("{}.{}", name, n),
+        };
+        *count += 1;
+
+        self.names.push(unique_name);
+        self.bases.push(name.to_string());
+        Var {
+            index: self.names.len() - 1,
+            global: false,
+        }
+    }
+}
+
+impl Var {
+    pub fn new(name: &str) -> Self {
+        SYMBOLS.lock().unwrap().add(name)
+    }
+
+    // 同じ名前の新しい変数。SSA形式で変数の版を分けるのに使う
+    pub fn new_version(&self) -> Self {
+        let mut symbols = SYMBOLS.lock().unwrap();
+        let base = symbols.bases[self.index].clone();
+        symbols.add(&base)
+    }
+
+    // 同じVMでの実行をまたいで値が残る変数
+    pub fn global(name: &str) -> Self {
+        let mut symbols = SYMBOLS.lock().unwrap();
+        if let Some(var) = symbols.globals.get(name) {
+            return *var;
+        }
+
+        symbols.names.push(format!("{}{}", GLOBAL_PREFIX, name));
+        symbols.bases.push(name.to_string());
+        let var = Self {
+            index: symbols.names.len() - 1,
+            global: true,
+        };
+        symbols.globals.insert(name.to_string(), var);
+        var
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.index
+    }
+
+    pub fn name(&self) -> String {
+        SYMBOLS.lock().unwrap().names[self.index].clone()
+    }
+
+    // 番号を付ける前の名前。new_version()で作った変数は元の変数と同じになる
+    pub fn base_name(&self) -> String {
+        SYMBOLS.lock().unwrap().bases[self.index].clone()
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+}
+
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+    I64,
+    Bool,
+    F64,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::I64 => write!(f, "i64"),
+            Type::Bool => write!(f, "bool"),
+            Type::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalError {
+    DivisionByZero,
+    TypeMismatch,
+}
+
+// VMと定数畳み込みで同じ結果になるように、値の演算はここで定義する
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Float(f64),
+}
+
+impl Value {
+    pub fn ty(&self) -> Type {
+        match self {
+            Self::Int(_) => Type::I64,
+            Self::Bool(_) => Type::Bool,
+            Self::Float(_) => Type::F64,
+        }
+    }
+
+    // JumpIfZeroで分岐する値かどうか
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Self::Int(n) => *n == 0,
+            Self::Bool(b) => !*b,
+            Self::Float(x) => *x == 0.0,
+        }
+    }
+
+    // 整数は0を偽として、0か1を返す
+    pub fn logical_not(self) -> Result<Value, EvalError> {
+        match self {
+            Self::Bool(b) => Ok(Self::Bool(!b)),
+            Self::Int(n) => Ok(Self::Int((n == 0) as i64)),
+            _ => Err(EvalError::TypeMismatch),
+        }
+    }
+
+    // 浮動小数点数から整数への変換は飽和し、NaNは0になる
+    pub fn convert(self, ty: Type) -> Value {
+        match (self, ty) {
+            (Self::Int(n), Type::Bool) => Self::Bool(n != 0),
+            (Self::Int(n), Type::F64) => Self::Float(n as f64),
+            (Self::Bool(b), Type::I64) => Self::Int(b as i64),
+            (Self::Bool(b), Type::F64) => Self::Float(b as i64 as f64),
+            (Self::Float(x), Type::I64) => Self::Int(x as i64),
+            (Self::Float(x), Type::Bool) => Self::Bool(x != 0.0),
+            (value, _) => value,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Int(n) => write!(f, "{}", n),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Float(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+impl From<Value> for Expr {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Int(n) => Expr::Int(n),
+            Value::Bool(b) => Expr::Bool(b),
+            Value::Float(x) => Expr::Float(x),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    UShr,
+}
+
+impl BinOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "^",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::UShr => ">>>",
+        }
+    }
+
+    // 整数の演算は折り返し、シフト量は64で割った余りとして扱う
+    // 浮動小数点数の演算はIEEE 754に従う
+    pub fn eval(self, lhs: Value, rhs: Value) -> Result<Value, EvalError> {
+        use Value::{Bool, Float, Int};
+
+        let value = match (self, lhs, rhs) {
+            (BinOp::Div, Int(_), Int(0)) | (BinOp::Mod, Int(_), Int(0)) => {
+                return Err(EvalError::DivisionByZero)
+            }
+            (BinOp::Add, Int(a), Int(b)) => Int(a.wrapping_add(b)),
+            (BinOp::Sub, Int(a), Int(b)) => Int(a.wrapping_sub(b)),
+            (BinOp::Mul, Int(a), Int(b)) => Int(a.wrapping_mul(b)),
+            (BinOp::Div, Int(a), Int(b)) => Int(a.wrapping_div(b)),
+            (BinOp::Mod, Int(a), Int(b)) => Int(a.wrapping_rem(b)),
+            (BinOp::Add, Float(a), Float(b)) => Float(a + b),
+            (BinOp::Sub, Float(a), Float(b)) => Float(a - b),
+            (BinOp::Mul, Float(a), Float(b)) => Float(a * b),
+            (BinOp::Div, Float(a), Float(b)) => Float(a / b),
+            (BinOp::Mod, Float(a), Float(b)) => Float(a % b),
+            (BinOp::Eq, a, b) if a.ty() == b.ty() => Bool(a == b),
+            (BinOp::Ne, a, b) if a.ty() == b.ty() => Bool(a != b),
+            (BinOp::Lt, Int(a), Int(b)) => Bool(a < b),
+            (BinOp::Le, Int(a), Int(b)) => Bool(a <= b),
+            (BinOp::Gt, Int(a), Int(b)) => Bool(a > b),
+            (BinOp::Ge, Int(a), Int(b)) => Bool(a >= b),
+            (BinOp::Lt, Float(a), Float(b)) => Bool(a < b),
+            (BinOp::Le, Float(a), Float(b)) => Bool(a <= b),
+            (BinOp::Gt, Float(a), Float(b)) => Bool(a > b),
+            (BinOp::Ge, Float(a), Float(b)) => Bool(a >= b),
+            (BinOp::And, Bool(a), Bool(b)) => Bool(a && b),
+            (BinOp::Or, Bool(a), Bool(b)) => Bool(a || b),
+            // 整数の論理演算は両辺を評価し、0か1を返す
+            (BinOp::And, Int(a), Int(b)) => Int((a != 0 && b != 0) as i64),
+            (BinOp::Or, Int(a), Int(b)) => Int((a != 0 || b != 0) as i64),
+            (BinOp::BitAnd, Int(a), Int(b)) => Int(a & b),
+            (BinOp::BitOr, Int(a), Int(b)) => Int(a | b),
+            (BinOp::BitXor, Int(a), Int(b)) => Int(a ^ b),
+            (BinOp::Shl, Int(a), Int(b)) => Int(a.wrapping_shl(b as u32)),
+            (BinOp::Shr, Int(a), Int(b)) => Int(a.wrapping_shr(b as u32)),
+            (BinOp::UShr, Int(a), Int(b)) => Int((a as u64).wrapping_shr(b as u32) as i64),
+            _ => return Err(EvalError::TypeMismatch),
+        };
+
+        Ok(value)
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub enum Expr {
     Int(i64),
+    Bool(bool),
+    Float(f64),
     Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
     Mul(Box<Expr>, Box<Expr>),
-    LoadCopy(isize),
+    Div(Box<Expr>, Box<Expr>),
+    Mod(Box<Expr>, Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+    Lt(Box<Expr>, Box<Expr>),
+    Le(Box<Expr>, Box<Expr>),
+    Gt(Box<Expr>, Box<Expr>),
+    Ge(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    // 短絡評価しない論理演算
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    BitAnd(Box<Expr>, Box<Expr>),
+    BitOr(Box<Expr>, Box<Expr>),
+    BitXor(Box<Expr>, Box<Expr>),
+    // シフト量は下位6ビットのみを使う
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
+    UShr(Box<Expr>, Box<Expr>),
+    // 条件によらず両方の式を評価し、分岐せずに選択する
+    Select(Box<Expr>, Box<Expr>, Box<Expr>),
+    // 型の変換
+    Convert(Type, Box<Expr>),
+    LoadCopy(Var),
+    Call(String, Vec<Expr>),
+    // メモリのアドレスから読み込む
+    Load(Box<Expr>),
+    // VMの入力から値を一つ読む
+    Read,
+    // 文字列リテラル。printの引数にだけ使える
+    Str(String),
 }
 
 impl Expr {
-    pub fn is_const(&self) -> bool {
+    pub fn from_binop(op: BinOp, lhs: Expr, rhs: Expr) -> Self {
+        let lhs = Box::new(lhs);
+        let rhs = Box::new(rhs);
+        match op {
+            BinOp::Add => Self::Add(lhs, rhs),
+            BinOp::Sub => Self::Sub(lhs, rhs),
+            BinOp::Mul => Self::Mul(lhs, rhs),
+            BinOp::Div => Self::Div(lhs, rhs),
+            BinOp::Mod => Self::Mod(lhs, rhs),
+            BinOp::Eq => Self::Eq(lhs, rhs),
+            BinOp::Ne => Self::Ne(lhs, rhs),
+            BinOp::Lt => Self::Lt(lhs, rhs),
+            BinOp::Le => Self::Le(lhs, rhs),
+            BinOp::Gt => Self::Gt(lhs, rhs),
+            BinOp::Ge => Self::Ge(lhs, rhs),
+            BinOp::And => Self::And(lhs, rhs),
+            BinOp::Or => Self::Or(lhs, rhs),
+            BinOp::BitAnd => Self::BitAnd(lhs, rhs),
+            BinOp::BitOr => Self::BitOr(lhs, rhs),
+            BinOp::BitXor => Self::BitXor(lhs, rhs),
+            BinOp::Shl => Self::Shl(lhs, rhs),
+            BinOp::Shr => Self::Shr(lhs, rhs),
+            BinOp::UShr => Self::UShr(lhs, rhs),
+        }
+    }
+
+    // 二項演算であれば演算子と両辺を返す
+    pub fn binop(&self) -> Option<(BinOp, &Expr, &Expr)> {
         match self {
-            Self::Int(_) => true,
-            Self::Add(lhs, rhs) | Self::Mul(lhs, rhs) => lhs.is_const() && rhs.is_const(),
-            _ => false,
+            Self::Add(lhs, rhs) => Some((BinOp::Add, lhs, rhs)),
+            Self::Sub(lhs, rhs) => Some((BinOp::Sub, lhs, rhs)),
+            Self::Mul(lhs, rhs) => Some((BinOp::Mul, lhs, rhs)),
+            Self::Div(lhs, rhs) => Some((BinOp::Div, lhs, rhs)),
+            Self::Mod(lhs, rhs) => Some((BinOp::Mod, lhs, rhs)),
+            Self::Eq(lhs, rhs) => Some((BinOp::Eq, lhs, rhs)),
+            Self::Ne(lhs, rhs) => Some((BinOp::Ne, lhs, rhs)),
+            Self::Lt(lhs, rhs) => Some((BinOp::Lt, lhs, rhs)),
+            Self::Le(lhs, rhs) => Some((BinOp::Le, lhs, rhs)),
+            Self::Gt(lhs, rhs) => Some((BinOp::Gt, lhs, rhs)),
+            Self::Ge(lhs, rhs) => Some((BinOp::Ge, lhs, rhs)),
+            Self::And(lhs, rhs) => Some((BinOp::And, lhs, rhs)),
+            Self::Or(lhs, rhs) => Some((BinOp::Or, lhs, rhs)),
+            Self::BitAnd(lhs, rhs) => Some((BinOp::BitAnd, lhs, rhs)),
+            Self::BitOr(lhs, rhs) => Some((BinOp::BitOr, lhs, rhs)),
+            Self::BitXor(lhs, rhs) => Some((BinOp::BitXor, lhs, rhs)),
+            Self::Shl(lhs, rhs) => Some((BinOp::Shl, lhs, rhs)),
+            Self::Shr(lhs, rhs) => Some((BinOp::Shr, lhs, rhs)),
+            Self::UShr(lhs, rhs) => Some((BinOp::UShr, lhs, rhs)),
+            _ => None,
+        }
+    }
+
+    pub fn binop_mut(&mut self) -> Option<(BinOp, &mut Expr, &mut Expr)> {
+        match self {
+            Self::Add(lhs, rhs) => Some((BinOp::Add, lhs, rhs)),
+            Self::Sub(lhs, rhs) => Some((BinOp::Sub, lhs, rhs)),
+            Self::Mul(lhs, rhs) => Some((BinOp::Mul, lhs, rhs)),
+            Self::Div(lhs, rhs) => Some((BinOp::Div, lhs, rhs)),
+            Self::Mod(lhs, rhs) => Some((BinOp::Mod, lhs, rhs)),
+            Self::Eq(lhs, rhs) => Some((BinOp::Eq, lhs, rhs)),
+            Self::Ne(lhs, rhs) => Some((BinOp::Ne, lhs, rhs)),
+            Self::Lt(lhs, rhs) => Some((BinOp::Lt, lhs, rhs)),
+            Self::Le(lhs, rhs) => Some((BinOp::Le, lhs, rhs)),
+            Self::Gt(lhs, rhs) => Some((BinOp::Gt, lhs, rhs)),
+            Self::Ge(lhs, rhs) => Some((BinOp::Ge, lhs, rhs)),
+            Self::And(lhs, rhs) => Some((BinOp::And, lhs, rhs)),
+            Self::Or(lhs, rhs) => Some((BinOp::Or, lhs, rhs)),
+            Self::BitAnd(lhs, rhs) => Some((BinOp::BitAnd, lhs, rhs)),
+            Self::BitOr(lhs, rhs) => Some((BinOp::BitOr, lhs, rhs)),
+            Self::BitXor(lhs, rhs) => Some((BinOp::BitXor, lhs, rhs)),
+            Self::Shl(lhs, rhs) => Some((BinOp::Shl, lhs, rhs)),
+            Self::Shr(lhs, rhs) => Some((BinOp::Shr, lhs, rhs)),
+            Self::UShr(lhs, rhs) => Some((BinOp::UShr, lhs, rhs)),
+            _ => None,
         }
     }
 
-    pub fn to_value(&self) -> i64 {
+    // 直下の部分式
+    pub fn subexprs(&self) -> Vec<&Expr> {
+        if let Some((_, lhs, rhs)) = self.binop() {
+            return vec![lhs, rhs];
+        }
+
         match self {
-            Self::Int(n) => *n,
-            Self::Add(lhs, rhs) => lhs.to_value() + rhs.to_value(),
-            Self::Mul(lhs, rhs) => lhs.to_value() * rhs.to_value(),
-            _ => panic!("`{}` is not constant", self),
+            Self::Not(expr) | Self::Convert(_, expr) | Self::Load(expr) => vec![expr],
+            Self::Select(cond, then, els) => vec![cond, then, els],
+            Self::Call(_, args) => args.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn subexprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Self::Not(expr) | Self::Convert(_, expr) | Self::Load(expr) => vec![expr],
+            Self::Select(cond, then, els) => vec![cond, then, els],
+            Self::Call(_, args) => args.iter_mut().collect(),
+            expr => match expr.binop_mut() {
+                Some((_, lhs, rhs)) => vec![lhs, rhs],
+                None => Vec::new(),
+            },
+        }
+    }
+
+    // 式の中で読む変数。同じ変数は読む回数だけ含まれる
+    pub fn used_vars(&self) -> Vec<Var> {
+        match self {
+            Self::LoadCopy(var) => vec![*var],
+            _ => self
+                .subexprs()
+                .into_iter()
+                .flat_map(Expr::used_vars)
+                .collect(),
+        }
+    }
+
+    // 副作用もトラップも起こさない式かどうか
+    // 投機的な評価や削除をしてよい
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Self::Div(lhs, rhs) | Self::Mod(lhs, rhs) => {
+                let divisor = rhs.eval_const();
+                lhs.is_pure() && divisor.is_some() && divisor != Some(Value::Int(0))
+            }
+            // 呼び出し先で何が起こるかわからない
+            Self::Call(_, _) => false,
+            // 範囲外のアドレスでトラップする
+            Self::Load(_) => false,
+            // 読むたびに入力を消費する
+            Self::Read => false,
+            _ => self.subexprs().into_iter().all(Expr::is_pure),
+        }
+    }
+
+    // 定数であれば評価した値を返す
+    // 実行時にトラップする式は定数として扱わない
+    pub fn eval_const(&self) -> Option<Value> {
+        match self {
+            Self::Int(n) => Some(Value::Int(*n)),
+            Self::Bool(b) => Some(Value::Bool(*b)),
+            Self::Float(x) => Some(Value::Float(*x)),
+            Self::Not(expr) => expr.eval_const()?.logical_not().ok(),
+            Self::Convert(ty, expr) => Some(expr.eval_const()?.convert(*ty)),
+            Self::Select(cond, then, els) => {
+                let cond = cond.eval_const()?;
+                let then = then.eval_const()?;
+                let els = els.eval_const()?;
+                if then.ty() != els.ty() {
+                    return None;
+                }
+
+                Some(if cond.is_zero() { els } else { then })
+            }
+            _ => {
+                let (op, lhs, rhs) = self.binop()?;
+                op.eval(lhs.eval_const()?, rhs.eval_const()?).ok()
+            }
+        }
+    }
+
+    pub fn is_const(&self) -> bool {
+        self.eval_const().is_some()
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self.eval_const() {
+            Some(value) => value,
+            None => panic!("`{}` is not constant", self),
         }
     }
 }
 
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((op, lhs, rhs)) = self.binop() {
+            return write!(f, "{} {} {}", lhs, op, rhs);
+        }
+
         match self {
             Expr::Int(n) => write!(f, "{}", n),
-            Expr::LoadCopy(loc) => write!(f, "v{}", loc),
-            Expr::Add(lhs, rhs) => write!(f, "{} + {}", lhs, rhs),
-            Expr::Mul(lhs, rhs) => write!(f, "{} * {}", lhs, rhs),
+            Expr::Bool(b) => write!(f, "{}", b),
+            Expr::Float(x) => write!(f, "{:?}", x),
+            Expr::LoadCopy(var) => write!(f, "{}", var),
+            Expr::Not(expr) => write!(f, "!{}", expr),
+            Expr::Select(cond, then, els) => write!(f, "select({}, {}, {})", cond, then, els),
+            Expr::Convert(ty, expr) => write!(f, "({} as {})", expr, ty),
+            Expr::Call(name, args) => write!(f, "{}({})", name, FormatIter(args, ", ")),
+            Expr::Load(addr) => write!(f, "[{}]", addr),
+            Expr::Read => write!(f, "read()"),
+            Expr::Str(s) => write!(f, "{:?}", s),
+            _ => unreachable!(),
         }
     }
 }
@@ -60,14 +554,40 @@ impl fmt::Debug for Expr {
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq)]
 pub enum Stmt {
-    Store(isize, Expr),
+    Store(Var, Expr),
     Expr(Expr),
     Label(Label),
     Jump(Label),
     JumpIfZero(Expr, Label),
     Print(Expr),
+    // 改行せずに出力する
+    PrintNoNewline(Expr),
+    Return(Expr),
+    // アドレスと値
+    StoreMem(Expr, Expr),
+    // 値ごとの飛び先と、どの値にも一致しない場合の飛び先
+    Switch(Expr, Vec<(i64, Label)>, Label),
+    // 条件が0であればメッセージとともに実行を中断する
+    Assert(Expr, String),
+    Trap(String),
+    // 構造化された制御文
+    // lower_control_flow()でラベルとジャンプに変換してから他のパスに渡す
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Block(Vec<Stmt>),
+    // 変数を宣言するブロック。宣言した変数はブロックの外では使えない
+    Scope(Vec<Var>, Vec<Stmt>),
+    // 最も内側のWhileを抜ける、または次の繰り返しに移る
+    Break,
+    Continue,
+    // Scopeの終わり。これ以降、変数の値は使われない
+    // lower_control_flow()がScopeの末尾とScopeから抜けるジャンプの前に置く
+    EndScope(Vec<Var>),
+    // SSA形式の合流点で、直前に実行したブロックのラベルに応じて変数を選ぶ
+    // ブロックの先頭のラベルの直後に置く。destruct_ssa()でコピーに変換してから他のパスに渡す
+    Phi(Var, Vec<(Label, Var)>),
 }
 
 impl Stmt {
@@ -80,16 +600,97 @@ impl Stmt {
 
     pub fn is_jump(&self) -> bool {
         match self {
-            Self::Jump(_) | Self::JumpIfZero(_, _) => true,
+            Self::Jump(_) | Self::JumpIfZero(_, _) | Self::Switch(_, _, _) => true,
             _ => false,
         }
     }
+
+    // 文が直接持つ式。構造化された制御文の本体の式は含まない
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Self::Store(_, expr)
+            | Self::Expr(expr)
+            | Self::JumpIfZero(expr, _)
+            | Self::Print(expr)
+            | Self::PrintNoNewline(expr)
+            | Self::Return(expr)
+            | Self::Switch(expr, _, _)
+            | Self::Assert(expr, _)
+            | Self::If(expr, _, _)
+            | Self::While(expr, _) => vec![expr],
+            Self::StoreMem(addr, expr) => vec![addr, expr],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Self::Store(_, expr)
+            | Self::Expr(expr)
+            | Self::JumpIfZero(expr, _)
+            | Self::Print(expr)
+            | Self::PrintNoNewline(expr)
+            | Self::Return(expr)
+            | Self::Switch(expr, _, _)
+            | Self::Assert(expr, _)
+            | Self::If(expr, _, _)
+            | Self::While(expr, _) => vec![expr],
+            Self::StoreMem(addr, expr) => vec![addr, expr],
+            _ => Vec::new(),
+        }
+    }
+
+    // 文が読む変数
+    pub fn used_vars(&self) -> Vec<Var> {
+        match self {
+            Self::Phi(_, args) => args.iter().map(|(_, var)| *var).collect(),
+            _ => self.exprs().into_iter().flat_map(Expr::used_vars).collect(),
+        }
+    }
+
+    // 飛ぶ可能性のあるラベル
+    pub fn jump_targets(&self) -> Vec<Label> {
+        match self {
+            Self::Jump(label) | Self::JumpIfZero(_, label) => vec![*label],
+            Self::Switch(_, cases, default) => cases
+                .iter()
+                .map(|(_, label)| *label)
+                .chain(std::iter::once(*default))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn jump_targets_mut(&mut self) -> Vec<&mut Label> {
+        match self {
+            Self::Jump(label) | Self::JumpIfZero(_, label) => vec![label],
+            Self::Switch(_, cases, default) => cases
+                .iter_mut()
+                .map(|(_, label)| label)
+                .chain(std::iter::once(default))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // 基本ブロックの最後の文になるかどうか
+    pub fn is_terminator(&self) -> bool {
+        self.is_jump() || matches!(self, Self::Return(_) | Self::Trap(_))
+    }
+
+    // 次の文に制御が移る可能性があるかどうか
+    pub fn falls_through(&self) -> bool {
+        !matches!(
+            self,
+            Self::Jump(_) | Self::Return(_) | Self::Switch(_, _, _) | Self::Trap(_)
+        )
+    }
 }
 
 impl fmt::Display for Stmt {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Stmt::Store(loc, expr) => write!(f, "v{} <- {}", loc, expr),
+            Stmt::Store(var, expr) => write!(f, "{} <- {}", var, expr),
             Stmt::Expr(expr) => write!(f, "{};", expr),
             Stmt::Label(label) => write!(f, "L{}:", label.as_usize()),
             Stmt::Jump(label) => write!(f, "jump L{}", label.as_usize()),
@@ -97,6 +698,50 @@ impl fmt::Display for Stmt {
                 write!(f, "jump_if_zero {} -> L{}", expr, label.as_usize())
             }
             Stmt::Print(expr) => write!(f, "print ({})", expr),
+            Stmt::PrintNoNewline(expr) => write!(f, "print_no_newline ({})", expr),
+            Stmt::Return(expr) => write!(f, "return {}", expr),
+            Stmt::StoreMem(addr, expr) => write!(f, "[{}] <- {}", addr, expr),
+            Stmt::Assert(expr, message) => write!(f, "assert {}, {:?}", expr, message),
+            Stmt::Trap(message) => write!(f, "trap {:?}", message),
+            Stmt::If(cond, then, els) if els.is_empty() => {
+                write!(f, "if {} {{ {} }}", cond, FormatIter(then, "; "))
+            }
+            Stmt::If(cond, then, els) => write!(
+                f,
+                "if {} {{ {} }} else {{ {} }}",
+                cond,
+                FormatIter(then, "; "),
+                FormatIter(els, "; ")
+            ),
+            Stmt::While(cond, body) => write!(f, "while {} {{ {} }}", cond, FormatIter(body, "; ")),
+            Stmt::Block(body) => write!(f, "{{ {} }}", FormatIter(body, "; ")),
+            Stmt::Scope(vars, body) => write!(
+                f,
+                "scope {} {{ {} }}",
+                FormatIter(vars, ", "),
+                FormatIter(body, "; ")
+            ),
+            Stmt::Break => write!(f, "break"),
+            Stmt::Continue => write!(f, "continue"),
+            Stmt::EndScope(vars) => write!(f, "end_scope {}", FormatIter(vars, ", ")),
+            Stmt::Phi(var, args) => {
+                let args = args
+                    .iter()
+                    .map(|(label, arg)| format!("L{}: {}", label.as_usize(), arg));
+                write!(f, "{} <- phi({})", var, FormatIter(args, ", "))
+            }
+            Stmt::Switch(expr, cases, default) => {
+                let cases = cases
+                    .iter()
+                    .map(|(value, label)| format!("{} -> L{}", value, label.as_usize()));
+                write!(
+                    f,
+                    "switch {} [{}] default L{}",
+                    expr,
+                    FormatIter(cases, ", "),
+                    default.as_usize()
+                )
+            }
         }
     }
 }
@@ -107,7 +752,7 @@ impl fmt::Debug for Stmt {
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq)]
 pub struct BasicBlock {
     pub stmts: Vec<Stmt>,
 }
@@ -124,7 +769,7 @@ pub fn stmts_to_bbs(stmts: Vec<Stmt>) -> Vec<BasicBlock> {
 
     for stmt in stmts {
         if let Some(curr) = &mut curr_bb {
-            let is_jump = stmt.is_jump();
+            let is_jump = stmt.is_terminator();
 
             match stmt {
                 Stmt::Label(label) if !is_jump => {
@@ -143,14 +788,19 @@ pub fn stmts_to_bbs(stmts: Vec<Stmt>) -> Vec<BasicBlock> {
                 }
             }
         } else {
-            curr_bb = Some(BasicBlock::new());
-            let curr_bb = curr_bb.as_mut().unwrap();
-
+            let mut bb = BasicBlock::new();
             if !stmt.is_label() {
-                curr_bb.stmts.push(Stmt::Label(Label::new()));
+                bb.stmts.push(Stmt::Label(Label::new()));
             }
 
-            curr_bb.stmts.push(stmt);
+            // ブロックの最初の文が分岐であれば、そこでブロックを終える
+            if stmt.is_terminator() {
+                bb.stmts.push(stmt);
+                bbs.push(bb);
+            } else {
+                bb.stmts.push(stmt);
+                curr_bb = Some(bb);
+            }
         }
     }
 
@@ -160,3 +810,122 @@ pub fn stmts_to_bbs(stmts: Vec<Stmt>) -> Vec<BasicBlock> {
 
     bbs
 }
+
+#[derive(Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    // 引数の変数と型
+    pub params: Vec<(Var, Type)>,
+    pub ret: Type,
+    pub body: Vec<Stmt>,
+}
+
+impl Function {
+    pub fn new(name: &str, params: Vec<(Var, Type)>, ret: Type, body: Vec<Stmt>) -> Self {
+        Self {
+            name: name.to_string(),
+            params,
+            ret,
+            body,
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params = self
+            .params
+            .iter()
+            .map(|(var, ty)| format!("{}: {}", var, ty));
+        writeln!(
+            f,
+            "fn {}({}) -> {}:",
+            self.name,
+            FormatIter(params, ", "),
+            self.ret
+        )?;
+        for stmt in &self.body {
+            writeln!(f, "  {}", stmt)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Clone, PartialEq, Default)]
+pub struct Module {
+    pub functions: Vec<Function>,
+}
+
+impl Module {
+    pub fn new() -> Self {
+        Self {
+            functions: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, func: Function) {
+        self.functions.push(func);
+    }
+
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|func| func.name == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Expr::*;
+
+    #[test]
+    fn test_stmts_to_bbs() {
+        let x = Var::new("x");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // 先頭の分岐と、続けて置いたジャンプはそれぞれ一つのブロックになる
+        let bbs = stmts_to_bbs(vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Jump(l1),
+            Stmt::Jump(l0),
+            Stmt::Label(l0),
+            Stmt::Store(x, Int(1)),
+            Stmt::Label(l1),
+            Stmt::Return(LoadCopy(x)),
+        ]);
+
+        assert_eq!(bbs.len(), 5);
+        assert!(bbs.iter().all(|bb| bb.stmts[0].is_label()));
+        assert_eq!(bbs[0].stmts[1], Stmt::JumpIfZero(Read, l0));
+        assert_eq!(bbs[0].stmts.len(), 2);
+        assert_eq!(bbs[1].stmts[1..], [Stmt::Jump(l1)]);
+        assert_eq!(bbs[2].stmts[1..], [Stmt::Jump(l0)]);
+        assert_eq!(
+            bbs[3].stmts,
+            vec![Stmt::Label(l0), Stmt::Store(x, Int(1)), Stmt::Jump(l1)]
+        );
+        assert_eq!(
+            bbs[4].stmts,
+            vec![Stmt::Label(l1), Stmt::Return(LoadCopy(x))]
+        );
+    }
+
+    #[test]
+    fn test_global_var() {
+        let global = Var::global("ir_global");
+        assert!(global.is_global());
+        assert_eq!(global, Var::global("ir_global"));
+        assert_eq!(global.name(), "@ir_global");
+
+        // 名前が'@'で始まってもローカル変数
+        let local = Var::new("@ir_global");
+        assert!(!local.is_global());
+        assert_ne!(local, global);
+        assert!(!global.new_version().is_global());
+    }
+}
@@ -1,19 +1,44 @@
+pub mod bitset;
+mod cse;
+mod dataflow;
+mod def_use;
+mod dominators;
 mod graph;
+mod hoisting;
+mod if_conversion;
 pub mod ir;
+mod liveness;
+mod loops;
+mod lower;
+mod sccp;
+mod ssa;
+mod typeck;
 mod vm;
 
+pub use bitset::BitSet;
+pub use cse::*;
+pub use dataflow::*;
+pub use def_use::*;
+pub use dominators::*;
 pub use graph::*;
+pub use hoisting::*;
+pub use if_conversion::*;
+pub use liveness::*;
+pub use loops::*;
+pub use lower::*;
+pub use sccp::*;
+pub use ssa::*;
+pub use typeck::*;
 pub use vm::*;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fmt;
 
-use ir::{Expr, Stmt};
-use maplit::hashset as hs;
+use ir::{BasicBlock, Expr, Stmt};
 
 use graph::DirectedGraph;
 
-struct FormatIter<'a, I, V>(I, &'a str)
+pub(crate) struct FormatIter<'a, I, V>(pub I, pub &'a str)
 where
     I: IntoIterator<Item = V> + Clone,
     V: fmt::Display;
@@ -40,30 +65,114 @@ where
     }
 }
 
+// nが2の累乗であればその指数を返す
+fn exact_log2(n: i64) -> Option<i64> {
+    if n > 0 && n & (n - 1) == 0 {
+        Some(n.trailing_zeros() as i64)
+    } else {
+        None
+    }
+}
+
+// 強度低減と単位元の除去
+// どの書き換えも被演算子の評価を省略しない
+fn reduce_strength(expr: &mut Expr) {
+    let new_expr = match expr {
+        // x * 2^k => x << k
+        Expr::Mul(lhs, rhs) => match (&**lhs, &**rhs) {
+            (x, Expr::Int(n)) | (Expr::Int(n), x) => match exact_log2(*n) {
+                Some(0) => x.clone(),
+                Some(k) => Expr::Shl(Box::new(x.clone()), Box::new(Expr::Int(k))),
+                None => return,
+            },
+            _ => return,
+        },
+        // x + 0, x | 0, x ^ 0 => x
+        Expr::Add(lhs, rhs) | Expr::BitOr(lhs, rhs) | Expr::BitXor(lhs, rhs) => {
+            match (&**lhs, &**rhs) {
+                (x, Expr::Int(0)) | (Expr::Int(0), x) => x.clone(),
+                _ => return,
+            }
+        }
+        // x & -1 => x
+        Expr::BitAnd(lhs, rhs) => match (&**lhs, &**rhs) {
+            (x, Expr::Int(-1)) | (Expr::Int(-1), x) => x.clone(),
+            _ => return,
+        },
+        // x - 0, x << 0, x >> 0, x >>> 0 => x
+        Expr::Sub(lhs, rhs) | Expr::Shl(lhs, rhs) | Expr::Shr(lhs, rhs) | Expr::UShr(lhs, rhs) => {
+            match &**rhs {
+                Expr::Int(0) => (**lhs).clone(),
+                _ => return,
+            }
+        }
+        _ => return,
+    };
+
+    *expr = new_expr;
+}
+
+// 最適化中に見つかった問題
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    // 問題のある文の位置
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "warning: {}: {}", self.index, self.message)
+    }
+}
+
 pub struct Optimizer {
-    // 到達定義
-    in_defs: Vec<HashSet<usize>>,
-    out_defs: Vec<HashSet<usize>>,
-    // 変数ごとの定義の集合
-    defs: HashMap<isize, HashSet<usize>>,
+    // 到達定義。集合の要素は定義の番号
+    in_defs: Vec<BitSet>,
+    out_defs: Vec<BitSet>,
+    // 入口での値(引数など)も定義として扱う
+    reaching: ReachingDefinitions,
+    // 文の直前で利用可能な複写
+    in_copies: Vec<BitSet>,
+    copies: AvailableCopies,
     // コードの有向グラフ
     code: DirectedGraph<Stmt>,
+    // 計算した到達定義を表示するかどうか
+    debug: bool,
 }
 
 impl Optimizer {
     pub fn new(code: Vec<Stmt>) -> Self {
+        // StoreMemは変数の定義として扱わない
+        // Loadは定数にも複写にもならないので、StoreMemをまたいで伝播されることはない
+        let code = code_to_graph(code);
         Self {
-            in_defs: vec![HashSet::new(); code.len()],
-            out_defs: vec![HashSet::new(); code.len()],
-            defs: HashMap::new(),
-            code: code_to_graph(code),
+            in_defs: vec![BitSet::new(); code.len()],
+            out_defs: vec![BitSet::new(); code.len()],
+            reaching: ReachingDefinitions::with_entry_definitions(&code),
+            in_copies: vec![BitSet::new(); code.len()],
+            copies: AvailableCopies::new(&code),
+            code,
+            debug: false,
         }
     }
 
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
     fn optimize_expr(&self, i: usize, expr: &mut Expr) {
         match expr {
             Expr::LoadCopy(loc) => {
-                let defs = match self.defs.get(&loc) {
+                // 複写伝播
+                // 全ての経路でloc <- srcが実行され、その後locもsrcも書き換えられていなければ、srcの複写に置き換える
+                if let Some(src) = self.copies.source(&self.in_copies[i], *loc) {
+                    *expr = Expr::LoadCopy(src);
+                    return;
+                }
+
+                let defs = match self.reaching.defs.get(&loc) {
                     Some(defs) => defs,
                     None => return,
                 };
@@ -72,38 +181,90 @@ impl Optimizer {
                 let reached_defs = defs & in_defs;
 
                 // 到達する定義が一つだけの場合
+                // 入口での値が届く場合は、値が分からないので伝播しない
                 if reached_defs.len() == 1 {
+                    let def = reached_defs.iter().next().unwrap();
+                    if self.reaching.is_entry_definition(def) {
+                        return;
+                    }
+
                     // 到達した唯一の定義とその式
-                    let only_def = reached_defs.into_iter().next().unwrap();
+                    let only_def = self.reaching.def_stmts[def];
                     let new_expr = match &self.code[only_def] {
                         Stmt::Store(_, expr) => expr,
                         stmt => panic!("the statement `{}` is not definition", stmt),
                     };
 
-                    // 複写伝播
-                    if let Expr::LoadCopy(loc) = new_expr {
-                        // only_defからiに至るパスにlocの定義がなければ、locの複写に置き換える
-                        let reached_defs = &self.defs[&loc] & &self.in_defs[only_def];
-                        let defs = &(in_defs & &self.defs[&loc]) - &reached_defs;
-                        if defs.is_empty() {
-                            *expr = Expr::LoadCopy(*loc);
-                            return;
-                        }
-                    }
-
                     // 定数伝播
                     // 到達した唯一の定義の式が定数であれば、その式で置き換える
                     if new_expr.is_const() {
-                        *expr = Expr::Int(new_expr.to_value())
+                        *expr = Expr::from(new_expr.to_value())
                     }
                 }
             }
-            Expr::Add(lhs, rhs) | Expr::Mul(lhs, rhs) => {
+            Expr::Add(lhs, rhs)
+            | Expr::Sub(lhs, rhs)
+            | Expr::Mul(lhs, rhs)
+            | Expr::Div(lhs, rhs)
+            | Expr::Mod(lhs, rhs)
+            | Expr::Eq(lhs, rhs)
+            | Expr::Ne(lhs, rhs)
+            | Expr::Lt(lhs, rhs)
+            | Expr::Le(lhs, rhs)
+            | Expr::Gt(lhs, rhs)
+            | Expr::Ge(lhs, rhs)
+            | Expr::And(lhs, rhs)
+            | Expr::Or(lhs, rhs)
+            | Expr::BitAnd(lhs, rhs)
+            | Expr::BitOr(lhs, rhs)
+            | Expr::BitXor(lhs, rhs)
+            | Expr::Shl(lhs, rhs)
+            | Expr::Shr(lhs, rhs)
+            | Expr::UShr(lhs, rhs) => {
                 self.optimize_expr(i, lhs);
                 self.optimize_expr(i, rhs);
 
                 if expr.is_const() {
-                    *expr = Expr::Int(expr.to_value());
+                    *expr = Expr::from(expr.to_value());
+                } else {
+                    reduce_strength(expr);
+                }
+            }
+            Expr::Not(operand) | Expr::Convert(_, operand) => {
+                self.optimize_expr(i, operand);
+
+                if expr.is_const() {
+                    *expr = Expr::from(expr.to_value());
+                }
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    self.optimize_expr(i, arg);
+                }
+            }
+            // メモリの内容は追跡しないので、アドレスの式だけを最適化する
+            Expr::Load(addr) => self.optimize_expr(i, addr),
+            // 副作用があるので、畳み込んだり複製したりしない
+            Expr::Read => {}
+            Expr::Select(cond, then, els) => {
+                self.optimize_expr(i, cond);
+                self.optimize_expr(i, then);
+                self.optimize_expr(i, els);
+
+                // 条件が定数であれば、選ばれない側の式を評価しなくてよい場合に限り畳み込む
+                if !cond.is_const() {
+                    return;
+                }
+
+                let (selected, other) = if !cond.to_value().is_zero() {
+                    (then, els)
+                } else {
+                    (els, then)
+                };
+
+                if other.is_pure() {
+                    let selected = std::mem::replace(&mut **selected, Expr::Int(0));
+                    *expr = selected;
                 }
             }
             _ => {}
@@ -116,70 +277,98 @@ impl Optimizer {
             Stmt::Expr(expr) => self.optimize_expr(i, expr),
             Stmt::JumpIfZero(expr, _) => self.optimize_expr(i, expr),
             Stmt::Print(expr) => self.optimize_expr(i, expr),
-            _ => {}
-        }
-    }
-
-    fn calc_reaching_definition(&mut self) {
-        // 変数ごとの定義の集合を計算
-        for (i, ir) in self.code.iter().enumerate() {
-            match ir {
-                Stmt::Store(loc, _) => {
-                    self.defs.entry(*loc).or_insert(HashSet::new()).insert(i);
+            Stmt::PrintNoNewline(expr) => self.optimize_expr(i, expr),
+            Stmt::Return(expr) => self.optimize_expr(i, expr),
+            Stmt::StoreMem(addr, expr) => {
+                self.optimize_expr(i, addr);
+                self.optimize_expr(i, expr);
+            }
+            Stmt::Assert(expr, _) => self.optimize_expr(i, expr),
+            Stmt::Switch(expr, cases, default) => {
+                self.optimize_expr(i, expr);
+
+                // 値が定数であれば、飛び先が一つに決まる
+                if let Expr::Int(n) = expr {
+                    let dest = cases
+                        .iter()
+                        .find(|(value, _)| value == n)
+                        .map(|(_, label)| *label)
+                        .unwrap_or(*default);
+                    *stmt = Stmt::Jump(dest);
                 }
-                _ => {}
             }
+            _ => {}
         }
+    }
 
-        loop {
-            let prev_in = self.in_defs.clone();
-            let prev_out = self.out_defs.clone();
-
-            for i in 0..self.code.len() {
-                self.in_defs[i] = self
-                    .code
-                    .pred_indexes(i)
-                    .map(|index| &self.out_defs[index])
-                    .fold(HashSet::new(), |acc, defs| &acc | defs);
+    fn calc_dataflow(&mut self) {
+        let result = dataflow::solve(&self.code, &self.reaching);
 
-                let (gen, kill) = match self.code[i] {
-                    Stmt::Store(loc, _) => (hs!(i), &self.defs[&loc] - &hs!(i)),
-                    _ => (hs!(), hs!()),
-                };
+        self.in_defs = result.ins;
+        self.out_defs = result.outs;
 
-                // out[i] = gen[i] U (in[i] - kill[i])
-                self.out_defs[i] = &gen | &(&self.in_defs[i] - &kill);
-            }
+        self.in_copies = dataflow::solve(&self.code, &self.copies).ins;
+    }
 
-            if self.in_defs == prev_in && self.out_defs == prev_out {
-                break;
-            }
+    // 表示用の定義の名前。Storeの位置か、入口での定義であればentry
+    fn def_name(&self, def: usize) -> String {
+        if self.reaching.is_entry_definition(def) {
+            "entry".to_string()
+        } else {
+            self.reaching.def_stmts[def].to_string()
         }
     }
 
-    pub fn optimize(mut self) -> Vec<Stmt> {
+    fn print_reaching_definitions(&self) {
         println!("-------------------");
-
-        self.calc_reaching_definition();
-
-        // 計算した到達定義を表示する
         for i in 0..self.code.len() {
             println!(
                 "{:<3} {:<15} in={} out={}",
                 i,
                 format!("{}", self.code[i]),
-                FormatIter(&self.in_defs[i], ","),
-                FormatIter(&self.out_defs[i], ",")
+                FormatIter(self.in_defs[i].iter().map(|def| self.def_name(def)), ","),
+                FormatIter(self.out_defs[i].iter().map(|def| self.def_name(def)), ",")
             );
         }
+    }
+
+    // 警告が要らない場合。警告はoptimize_with_warnings()で受け取る
+    pub fn optimize(self) -> Vec<Stmt> {
+        self.optimize_with_warnings().0
+    }
+
+    pub fn optimize_with_warnings(mut self) -> (Vec<Stmt>, Vec<Warning>) {
+        self.calc_dataflow();
+        if self.debug {
+            self.print_reaching_definitions();
+        }
 
         // 到達定義情報を元に最適化する
-        let mut new_code = self.code.clone();
-        for (i, stmt) in new_code.iter_mut().enumerate() {
-            self.optimize_stmt(i, stmt)
+        let mut new_code = Vec::with_capacity(self.code.len());
+        let mut warnings = Vec::new();
+        for (i, stmt) in self.code.iter().enumerate() {
+            let mut stmt = stmt.clone();
+            self.optimize_stmt(i, &mut stmt);
+
+            // 条件が定数になったアサーションは、常に成功するなら削除し、常に失敗するなら中断に置き換える
+            if let Stmt::Assert(expr, message) = &stmt {
+                if let Some(value) = expr.eval_const() {
+                    if value.is_zero() {
+                        warnings.push(Warning {
+                            index: i,
+                            message: format!("assertion always fails: {}", message),
+                        });
+                        stmt = Stmt::Trap(message.clone());
+                    } else {
+                        continue;
+                    }
+                }
+            }
+
+            new_code.push(stmt);
         }
 
-        new_code.into_iter().collect()
+        (new_code, warnings)
     }
 }
 
@@ -198,17 +387,13 @@ pub fn code_to_graph(code: Vec<Stmt>) -> DirectedGraph<Stmt> {
     }
 
     for index in 0..graph.len() {
-        match &graph[index] {
-            Stmt::Jump(name) | Stmt::JumpIfZero(_, name) => {
-                let dest_index = labels[name];
-                graph.add_edge(index, dest_index);
-            }
-            _ => {}
+        for name in graph[index].jump_targets() {
+            let dest_index = labels[&name];
+            graph.add_edge(index, dest_index);
         }
 
         if let Some(prev) = index.checked_sub(1) {
-            if let Stmt::Jump(_) = &graph[prev] {
-            } else {
+            if graph[prev].falls_through() {
                 graph.add_edge(prev, index);
             }
         }
@@ -217,8 +402,367 @@ pub fn code_to_graph(code: Vec<Stmt>) -> DirectedGraph<Stmt> {
     graph
 }
 
+// 制御依存グラフ。ノードはコードの文で、分岐からその分岐に制御依存する文に辺を張る
+// 文が実行されるかどうかを決める分岐は、その文の先行ノードになる
+// 無限ループの中の文から仮想的な出口に張った辺は辿られないので、実際の後続が二つ以上ある文だけを分岐とする
+pub fn control_dependence_graph(code: Vec<Stmt>) -> DirectedGraph<Stmt> {
+    let graph = code_to_graph(code);
+    let post_dominators = PostDominators::new(&graph);
+
+    let mut dependences = Vec::new();
+    for node in 0..graph.len() {
+        for branch in post_dominators.frontier(node) {
+            if graph.succ_indexes(branch).count() >= 2 {
+                dependences.push((branch, node));
+            }
+        }
+    }
+
+    let mut cdg = DirectedGraph::with_capacity(graph.len());
+    for stmt in graph.into_iter() {
+        cdg.add(stmt);
+    }
+    for (branch, node) in dependences {
+        cdg.add_edge(branch, node);
+    }
+
+    cdg
+}
+
+// 基本ブロックを有向グラフに変換する
+// stmts_to_bbs()で分割した、先頭がラベルの基本ブロックを対象にする
+pub fn bbs_to_graph(bbs: Vec<BasicBlock>) -> DirectedGraph<BasicBlock> {
+    let mut graph = DirectedGraph::new();
+    let mut labels = HashMap::new();
+
+    for bb in bbs {
+        let first = bb.stmts.first().cloned();
+        let index = graph.add(bb);
+        if let Some(Stmt::Label(name)) = first {
+            labels.insert(name, index);
+        }
+    }
+
+    for index in 0..graph.len() {
+        let last = match graph[index].stmts.last() {
+            Some(last) => last.clone(),
+            None => continue,
+        };
+        for name in last.jump_targets() {
+            graph.add_edge(index, labels[&name]);
+        }
+
+        if last.falls_through() && index + 1 < graph.len() {
+            graph.add_edge(index, index + 1);
+        }
+    }
+
+    graph
+}
+
 pub fn print_code(code: &[Stmt]) {
     for (i, stmt) in code.iter().enumerate() {
         println!("{:<3} {}", i, stmt);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ir::Expr::*;
+    use ir::Var;
+
+    #[test]
+    fn test_assert_removal() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let code = vec![
+            Stmt::Store(x, Int(5)),
+            Stmt::Assert(Eq(Box::new(LoadCopy(x)), Box::new(Int(5))), "a".to_string()),
+            Stmt::Assert(
+                Lt(Box::new(LoadCopy(x)), Box::new(LoadCopy(y))),
+                "b".to_string(),
+            ),
+            Stmt::Assert(Lt(Box::new(LoadCopy(x)), Box::new(Int(3))), "c".to_string()),
+        ];
+
+        let (code, warnings) = Optimizer::new(code).optimize_with_warnings();
+        assert_eq!(
+            code,
+            vec![
+                Stmt::Store(x, Int(5)),
+                Stmt::Assert(Lt(Box::new(Int(5)), Box::new(LoadCopy(y))), "b".to_string()),
+                Stmt::Trap("c".to_string()),
+            ]
+        );
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                index: 3,
+                message: "assertion always fails: c".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parameter_reaches_from_entry() {
+        let n = Var::new("n");
+        let l0 = ir::Label::new();
+        let body = vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Store(n, Int(1)),
+            Stmt::Label(l0),
+            Stmt::Return(LoadCopy(n)),
+        ];
+
+        // 分岐で代入を飛ばすと、引数の値のまま返る
+        let body = Optimizer::new(body).optimize();
+        assert_eq!(body[3], Stmt::Return(LoadCopy(n)));
+
+        let result = Var::global("entry_param");
+        let mut module = ir::Module::new();
+        module.add(ir::Function::new(
+            "f",
+            vec![(n, ir::Type::I64)],
+            ir::Type::I64,
+            body,
+        ));
+        module.add(ir::Function::new(
+            "main",
+            Vec::new(),
+            ir::Type::I64,
+            vec![Stmt::Store(result, Call("f".to_string(), vec![Int(5)]))],
+        ));
+
+        let mut vm = VM::new();
+        vm.set_input(vec![0]);
+        vm.run(&module_to_insts(&module).unwrap()).unwrap();
+        assert_eq!(vm.global(result), Some(ir::Value::Int(5)));
+    }
+
+    #[test]
+    fn test_copy_source_redefined() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let l3 = ir::Label::new();
+        let l4 = ir::Label::new();
+        let code = vec![
+            Stmt::Store(x, Int(1)),
+            Stmt::Label(l3),
+            Stmt::Store(y, LoadCopy(x)),
+            Stmt::JumpIfZero(Read, l4),
+            Stmt::Store(x, Read),
+            Stmt::Label(l4),
+            Stmt::Print(LoadCopy(y)),
+            Stmt::Print(LoadCopy(x)),
+            Stmt::Store(y, LoadCopy(x)),
+            Stmt::Print(LoadCopy(y)),
+            Stmt::JumpIfZero(Read, l3),
+        ];
+
+        // xを書き換える経路があるので、最初のprint yはxにならない
+        let code = Optimizer::new(code).optimize();
+        assert_eq!(code[6], Stmt::Print(LoadCopy(y)));
+        assert_eq!(code[9], Stmt::Print(LoadCopy(x)));
+    }
+
+    #[test]
+    fn test_fold_division() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let z = Var::new("z");
+        let code = vec![
+            Stmt::Store(x, Int(10)),
+            Stmt::Store(y, Div(Box::new(LoadCopy(x)), Box::new(Int(0)))),
+            Stmt::Store(z, Mod(Box::new(LoadCopy(x)), Box::new(Int(0)))),
+            Stmt::Print(Div(Box::new(Int(i64::MIN)), Box::new(Int(-1)))),
+            Stmt::Print(Mod(Box::new(Int(i64::MIN)), Box::new(Int(-1)))),
+        ];
+
+        // ゼロ除算は実行時にトラップするので畳み込まない
+        // オーバーフローする除算はVMと同じく折り返す
+        let (code, _) = Optimizer::new(code).optimize_with_warnings();
+        assert_eq!(
+            code,
+            vec![
+                Stmt::Store(x, Int(10)),
+                Stmt::Store(y, Div(Box::new(Int(10)), Box::new(Int(0)))),
+                Stmt::Store(z, Mod(Box::new(Int(10)), Box::new(Int(0)))),
+                Stmt::Print(Int(i64::MIN)),
+                Stmt::Print(Int(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_comparisons() {
+        let x = Var::new("x");
+        let a = Var::new("a");
+        let b = Var::new("b");
+        let l0 = ir::Label::new();
+        let code = vec![
+            Stmt::Store(x, Int(3)),
+            Stmt::Store(
+                a,
+                And(
+                    Box::new(Le(Box::new(LoadCopy(x)), Box::new(Int(3)))),
+                    Box::new(Not(Box::new(Ne(Box::new(LoadCopy(x)), Box::new(Int(3)))))),
+                ),
+            ),
+            Stmt::Store(
+                b,
+                Or(
+                    Box::new(Ge(Box::new(LoadCopy(x)), Box::new(Int(4)))),
+                    Box::new(Eq(Box::new(Float(1.5)), Box::new(Float(2.5)))),
+                ),
+            ),
+            // 条件が分かっている分岐は、条件が定数になる
+            Stmt::JumpIfZero(Gt(Box::new(LoadCopy(x)), Box::new(Int(2))), l0),
+            Stmt::Print(Lt(Box::new(LoadCopy(x)), Box::new(Read))),
+            Stmt::Label(l0),
+        ];
+
+        let (code, _) = Optimizer::new(code).optimize_with_warnings();
+        assert_eq!(
+            code,
+            vec![
+                Stmt::Store(x, Int(3)),
+                Stmt::Store(a, Bool(true)),
+                Stmt::Store(b, Bool(false)),
+                Stmt::JumpIfZero(Bool(true), l0),
+                Stmt::Print(Lt(Box::new(Int(3)), Box::new(Read))),
+                Stmt::Label(l0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reduce_strength() {
+        assert_eq!(exact_log2(1), Some(0));
+        assert_eq!(exact_log2(8), Some(3));
+        assert_eq!(exact_log2(1 << 62), Some(62));
+        assert_eq!(exact_log2(0), None);
+        assert_eq!(exact_log2(6), None);
+        assert_eq!(exact_log2(-8), None);
+        assert_eq!(exact_log2(i64::MIN), None);
+
+        let x = || Box::new(LoadCopy(Var::new("x")));
+        let reduce = |mut expr: Expr| {
+            reduce_strength(&mut expr);
+            expr
+        };
+
+        let lhs = x();
+        let expr = reduce(Mul(lhs.clone(), Box::new(Int(8))));
+        assert_eq!(expr, Shl(lhs.clone(), Box::new(Int(3))));
+        let expr = reduce(Mul(Box::new(Int(4)), lhs.clone()));
+        assert_eq!(expr, Shl(lhs.clone(), Box::new(Int(2))));
+        assert_eq!(reduce(Mul(lhs.clone(), Box::new(Int(1)))), *lhs);
+
+        // 負の数と2の累乗でない数はシフトにしない
+        for n in [-8, 6, 0, i64::MIN] {
+            let expr = Mul(lhs.clone(), Box::new(Int(n)));
+            assert_eq!(reduce(expr.clone()), expr);
+        }
+    }
+
+    #[test]
+    fn test_scope_end_kills_definitions() {
+        let t = Var::new("t");
+        let x = Var::new("x");
+        let code = vec![
+            Stmt::Scope(
+                vec![t],
+                vec![Stmt::Store(t, Int(5)), Stmt::Store(x, LoadCopy(t))],
+            ),
+            Stmt::Print(LoadCopy(t)),
+            Stmt::Print(LoadCopy(x)),
+        ];
+        let code = lower_control_flow(code).unwrap();
+
+        // xをtの複写に置き換えると、スコープの外でtを使うことになる
+        let (code, _) = Optimizer::new(code).optimize_with_warnings();
+        assert_eq!(
+            code,
+            vec![
+                Stmt::Store(t, Int(5)),
+                Stmt::Store(x, Int(5)),
+                Stmt::EndScope(vec![t]),
+                Stmt::Print(LoadCopy(t)),
+                Stmt::Print(LoadCopy(x)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_code_to_graph_after_trap() {
+        let graph = code_to_graph(vec![
+            Stmt::Trap("unreachable".to_string()),
+            Stmt::Print(Int(1)),
+            Stmt::Return(Int(0)),
+        ]);
+
+        // Trapの後の文には制御が移らない
+        assert_eq!(graph.succ_indexes(0).count(), 0);
+        assert_eq!(graph.pred_indexes(1).count(), 0);
+        assert_eq!(graph.succ_indexes(1).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_control_dependence_graph() {
+        let x = Var::new("x");
+        let l0 = ir::Label::new();
+        let l1 = ir::Label::new();
+        let cdg = control_dependence_graph(vec![
+            Stmt::Store(x, Read),
+            Stmt::JumpIfZero(LoadCopy(x), l0),
+            Stmt::Print(Int(1)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Print(Int(2)),
+            Stmt::Label(l1),
+            Stmt::Print(Int(3)),
+        ]);
+
+        let deps = |i| cdg.pred_indexes(i).collect::<Vec<_>>();
+        assert_eq!(deps(0), vec![]);
+        assert_eq!(deps(2), vec![1]);
+        assert_eq!(deps(5), vec![1]);
+        assert_eq!(deps(7), vec![]);
+        assert_eq!(cdg.succ_indexes(1).count(), 4);
+    }
+
+    #[test]
+    fn test_control_dependence_on_infinite_loop() {
+        let l0 = ir::Label::new();
+        let l1 = ir::Label::new();
+        let l2 = ir::Label::new();
+        // 0: jump_if_zero read -> L0
+        // 1: return 1
+        // 2: L0:
+        // 3: jump_if_zero read -> L2
+        // 4: L1:
+        // 5: print 2
+        // 6: jump L1
+        // 7: L2:
+        // 8: return 3
+        let cdg = control_dependence_graph(vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Return(Int(1)),
+            Stmt::Label(l0),
+            Stmt::JumpIfZero(Read, l2),
+            Stmt::Label(l1),
+            Stmt::Print(Int(2)),
+            Stmt::Jump(l1),
+            Stmt::Label(l2),
+            Stmt::Return(Int(3)),
+        ]);
+
+        // 無条件のジャンプは分岐にならない
+        let deps = |i| cdg.pred_indexes(i).collect::<Vec<_>>();
+        assert_eq!(deps(4), vec![3]);
+        assert_eq!(deps(5), vec![3]);
+        assert_eq!(deps(6), vec![3]);
+        assert_eq!(cdg.succ_indexes(6).count(), 0);
+    }
+}
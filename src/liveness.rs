@@ -0,0 +1,210 @@
+use crate::bitset::BitSet;
+use crate::code_to_graph;
+use crate::dataflow::{self, Analysis, Direction};
+use crate::graph::DirectedGraph;
+use crate::ir::{Stmt, Var};
+use std::collections::{HashMap, HashSet};
+
+// 生存変数解析。値はその後で読まれる可能性のある変数の番号の集合
+// グローバル変数は実行の後や呼び出した関数で読まれうるので、常に生きているものとして集合に含めない
+struct LiveVariables {
+    numbers: HashMap<Var, usize>,
+}
+
+impl LiveVariables {
+    fn set<I: IntoIterator<Item = Var>>(&self, vars: I) -> BitSet {
+        vars.into_iter()
+            .filter_map(|var| self.numbers.get(&var).copied())
+            .collect()
+    }
+}
+
+impl Analysis<Stmt> for LiveVariables {
+    type Domain = BitSet;
+
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn boundary(&self) -> Self::Domain {
+        BitSet::new()
+    }
+
+    fn initial(&self) -> Self::Domain {
+        BitSet::new()
+    }
+
+    fn meet(&self, acc: &mut Self::Domain, other: &Self::Domain) {
+        acc.union_with(other);
+    }
+
+    // in = use U (out - def)
+    fn transfer(&self, _: usize, stmt: &Stmt, output: &Self::Domain) -> Self::Domain {
+        let mut input = output.clone();
+        match stmt {
+            Stmt::Store(var, _) | Stmt::Phi(var, _) => input.difference_with(&self.set(vec![*var])),
+            // スコープの外では変数が読まれない
+            Stmt::EndScope(vars) => input.difference_with(&self.set(vars.iter().copied())),
+            _ => {}
+        }
+
+        input.union_with(&self.set(stmt.used_vars()));
+        input
+    }
+}
+
+// 文ごとの生存変数
+pub struct Liveness {
+    vars: Vec<Var>,
+    numbers: HashMap<Var, usize>,
+    live_in: Vec<BitSet>,
+    live_out: Vec<BitSet>,
+}
+
+impl Liveness {
+    pub fn new(code: &DirectedGraph<Stmt>) -> Self {
+        let mut vars = Vec::new();
+        let mut numbers = HashMap::new();
+        for stmt in code.iter() {
+            let defined = match stmt {
+                Stmt::Store(var, _) | Stmt::Phi(var, _) => Some(*var),
+                _ => None,
+            };
+            for var in stmt.used_vars().into_iter().chain(defined) {
+                if !var.is_global() && !numbers.contains_key(&var) {
+                    numbers.insert(var, vars.len());
+                    vars.push(var);
+                }
+            }
+        }
+
+        let analysis = LiveVariables { numbers };
+        let result = dataflow::solve(code, &analysis);
+
+        Self {
+            vars,
+            numbers: analysis.numbers,
+            live_in: result.ins,
+            live_out: result.outs,
+        }
+    }
+
+    fn is_live(&self, set: &BitSet, var: Var) -> bool {
+        match self.numbers.get(&var) {
+            Some(number) => set.contains(*number),
+            None => var.is_global(),
+        }
+    }
+
+    // i番目の文の直前で生きているかどうか
+    pub fn is_live_in(&self, i: usize, var: Var) -> bool {
+        self.is_live(&self.live_in[i], var)
+    }
+
+    // i番目の文の直後で生きているかどうか
+    pub fn is_live_out(&self, i: usize, var: Var) -> bool {
+        self.is_live(&self.live_out[i], var)
+    }
+
+    // i番目の文の直前で生きているグローバル変数以外の変数
+    pub fn live_in(&self, i: usize) -> HashSet<Var> {
+        self.live_in[i].iter().map(|n| self.vars[n]).collect()
+    }
+
+    // i番目の文の直後で生きているグローバル変数以外の変数
+    pub fn live_out(&self, i: usize) -> HashSet<Var> {
+        self.live_out[i].iter().map(|n| self.vars[n]).collect()
+    }
+}
+
+// 代入した値がその後で読まれないStoreを削除する
+// 右辺に副作用やトラップがある場合は、式文として残す
+pub fn dead_store_elimination(code: Vec<Stmt>) -> Vec<Stmt> {
+    let mut code = code;
+
+    // Storeを削除すると、その右辺で読んでいた変数のStoreも不要になることがあるので繰り返す
+    loop {
+        let graph = code_to_graph(code);
+        let liveness = Liveness::new(&graph);
+
+        let mut changed = false;
+        let mut new_code = Vec::with_capacity(graph.len());
+        for (i, stmt) in graph.into_iter().enumerate() {
+            match stmt {
+                Stmt::Store(var, expr) if !liveness.is_live_out(i, var) => {
+                    changed = true;
+                    if !expr.is_pure() {
+                        new_code.push(Stmt::Expr(expr));
+                    }
+                }
+                stmt => new_code.push(stmt),
+            }
+        }
+
+        code = new_code;
+        if !changed {
+            return code;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Expr::*, Label};
+
+    #[test]
+    fn test_liveness() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let l0 = Label::new();
+        // 0: x <- 1
+        // 1: L0:
+        // 2: y <- x + 1
+        // 3: x <- y
+        // 4: jump_if_zero y -> L0
+        // 5: return x
+        let code = code_to_graph(vec![
+            Stmt::Store(x, Int(1)),
+            Stmt::Label(l0),
+            Stmt::Store(y, Add(Box::new(LoadCopy(x)), Box::new(Int(1)))),
+            Stmt::Store(x, LoadCopy(y)),
+            Stmt::JumpIfZero(LoadCopy(y), l0),
+            Stmt::Return(LoadCopy(x)),
+        ]);
+
+        let liveness = Liveness::new(&code);
+        assert_eq!(liveness.live_in(0), HashSet::new());
+        assert_eq!(liveness.live_out(0), vec![x].into_iter().collect());
+        assert_eq!(liveness.live_in(3), vec![y].into_iter().collect());
+        assert_eq!(liveness.live_out(3), vec![x, y].into_iter().collect());
+        assert!(liveness.is_live_out(4, x));
+        assert!(!liveness.is_live_out(4, y));
+        assert!(liveness.is_live_out(5, Var::global("liveness")));
+    }
+
+    #[test]
+    fn test_dead_store_elimination() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let z = Var::new("z");
+        let g = Var::global("dse");
+        let code = vec![
+            Stmt::Store(x, Int(1)),
+            // yが読まれないので、xも読まれなくなる
+            Stmt::Store(y, Add(Box::new(LoadCopy(x)), Box::new(Int(1)))),
+            Stmt::Store(z, Read),
+            Stmt::Store(z, Int(3)),
+            Stmt::Store(g, Int(4)),
+            Stmt::Print(LoadCopy(z)),
+        ];
+
+        assert_eq!(
+            dead_store_elimination(code),
+            vec![
+                Stmt::Expr(Read),
+                Stmt::Store(z, Int(3)),
+                Stmt::Store(g, Int(4)),
+                Stmt::Print(LoadCopy(z)),
+            ]
+        );
+    }
+}
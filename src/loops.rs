@@ -0,0 +1,303 @@
+use crate::bitset::BitSet;
+use crate::code_to_graph;
+use crate::dominators::Dominators;
+use crate::graph::DirectedGraph;
+use crate::ir::{Label, Stmt};
+use std::collections::HashMap;
+
+// 自然ループ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    pub header: usize,
+    // ループに含まれるノード。ヘッダも含む
+    pub body: BitSet,
+    // ヘッダへの後ろ向きの辺の元
+    pub latches: Vec<usize>,
+    // ループの中から外への辺
+    pub exits: Vec<(usize, usize)>,
+    // 直接外側のループと、直接内側のループ
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    // 最も外側のループが1
+    pub depth: usize,
+}
+
+// ループの入れ子の森
+// ヘッダが同じ後ろ向きの辺は一つのループにまとめる
+// 支配されないノードへの辺で作られる既約なループは扱わない
+pub struct LoopForest {
+    loops: Vec<Loop>,
+    // ノードを含む最も内側のループ
+    innermost: Vec<Option<usize>>,
+}
+
+impl LoopForest {
+    pub fn new<T>(graph: &DirectedGraph<T>) -> Self {
+        let dominators = Dominators::new(graph, 0);
+
+        // ヘッダがその元を支配する辺が後ろ向きの辺
+        let mut latches: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (from, to) in graph.edges() {
+            for to in to {
+                if dominators.dominates(to, from) {
+                    latches.entry(to).or_default().push(from);
+                }
+            }
+        }
+
+        let mut loops: Vec<Loop> = latches
+            .into_iter()
+            .map(|(header, mut latches)| {
+                latches.sort_unstable();
+
+                // 後ろ向きの辺の元から、ヘッダを越えずに先行ノードを遡る
+                // 入口から到達できない先行ノードはループに含めない
+                let mut body = BitSet::new();
+                body.insert(header);
+                let mut stack = latches.clone();
+                while let Some(node) = stack.pop() {
+                    if body.insert(node) {
+                        stack.extend(
+                            graph
+                                .pred_indexes(node)
+                                .filter(|pred| dominators.is_reachable(*pred)),
+                        );
+                    }
+                }
+
+                let mut exits: Vec<(usize, usize)> = body
+                    .iter()
+                    .flat_map(|node| graph.succ_indexes(node).map(move |succ| (node, succ)))
+                    .filter(|(_, succ)| !body.contains(*succ))
+                    .collect();
+                exits.sort_unstable();
+
+                Loop {
+                    header,
+                    body,
+                    latches,
+                    exits,
+                    parent: None,
+                    children: Vec::new(),
+                    depth: 0,
+                }
+            })
+            .collect();
+
+        // 外側のループが先に来るように並べる
+        loops.sort_by_key(|l| (std::cmp::Reverse(l.body.len()), l.header));
+
+        // 自然ループは入れ子になるか交わらないので、ヘッダを含む最も小さいループが親になる
+        let mut innermost = vec![None; graph.len()];
+        for i in 0..loops.len() {
+            let parent = innermost[loops[i].header];
+            loops[i].parent = parent;
+            loops[i].depth = match parent {
+                Some(parent) => {
+                    loops[parent].children.push(i);
+                    loops[parent].depth + 1
+                }
+                None => 1,
+            };
+            for node in loops[i].body.iter() {
+                innermost[node] = Some(i);
+            }
+        }
+
+        Self { loops, innermost }
+    }
+
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+
+    // 最も外側のループ
+    pub fn roots(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.loops.len()).filter(move |i| self.loops[*i].parent.is_none())
+    }
+
+    pub fn innermost_loop(&self, node: usize) -> Option<usize> {
+        self.innermost[node]
+    }
+
+    // ノードを含むループの数。ループの外なら0
+    pub fn depth(&self, node: usize) -> usize {
+        match self.innermost[node] {
+            Some(i) => self.loops[i].depth,
+            None => 0,
+        }
+    }
+}
+
+// 各ループのヘッダの直前に、ループの外からだけ入る空のブロックを挿入する
+// ループの外からヘッダへのジャンプは挿入したラベルへのジャンプに書き換える
+pub fn insert_preheaders(code: Vec<Stmt>) -> Vec<Stmt> {
+    let graph = code_to_graph(code);
+    let forest = LoopForest::new(&graph);
+
+    // ヘッダの位置と、その直前に入れる文
+    let mut inserted: HashMap<usize, Vec<Stmt>> = HashMap::new();
+    // ヘッダにラベルがない場合に、ヘッダの前に付けるラベル
+    let mut header_labels: HashMap<usize, Label> = HashMap::new();
+    // ジャンプ先を書き換える文と、元のラベルと新しいラベル
+    let mut retargets: Vec<(usize, Label, Label)> = Vec::new();
+
+    for l in forest.loops() {
+        let preheader = Label::new();
+        let mut stmts = Vec::new();
+
+        // 直前の文がループの中からヘッダに落ちる場合は、プリヘッダを飛び越える
+        let header_label = match &graph[l.header] {
+            Stmt::Label(label) => *label,
+            _ => *header_labels.entry(l.header).or_insert_with(Label::new),
+        };
+        if let Some(prev) = l.header.checked_sub(1) {
+            if l.body.contains(prev) && graph[prev].falls_through() {
+                stmts.push(Stmt::Jump(header_label));
+            }
+        }
+        stmts.push(Stmt::Label(preheader));
+        inserted.insert(l.header, stmts);
+
+        if let Stmt::Label(label) = &graph[l.header] {
+            for pred in graph.pred_indexes(l.header) {
+                if !l.body.contains(pred) {
+                    retargets.push((pred, *label, preheader));
+                }
+            }
+        }
+    }
+
+    let mut code: Vec<Stmt> = graph.into_iter().collect();
+    for (stmt, from, to) in retargets {
+        for label in code[stmt].jump_targets_mut() {
+            if *label == from {
+                *label = to;
+            }
+        }
+    }
+
+    let mut new_code = Vec::with_capacity(code.len() + inserted.len() * 2);
+    for (i, stmt) in code.into_iter().enumerate() {
+        if let Some(stmts) = inserted.remove(&i) {
+            new_code.extend(stmts);
+        }
+        if let Some(label) = header_labels.remove(&i) {
+            new_code.push(Stmt::Label(label));
+        }
+        new_code.push(stmt);
+    }
+
+    new_code
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Expr::*, Var};
+
+    #[test]
+    fn test_loop_forest() {
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let l2 = Label::new();
+        // 0: print 0
+        // 1: L0:
+        // 2: L1:
+        // 3: jump_if_zero read -> L1
+        // 4: jump_if_zero read -> L2
+        // 5: jump L0
+        // 6: L2:
+        // 7: return 0
+        let code = code_to_graph(vec![
+            Stmt::Print(Int(0)),
+            Stmt::Label(l0),
+            Stmt::Label(l1),
+            Stmt::JumpIfZero(Read, l1),
+            Stmt::JumpIfZero(Read, l2),
+            Stmt::Jump(l0),
+            Stmt::Label(l2),
+            Stmt::Return(Int(0)),
+        ]);
+
+        let forest = LoopForest::new(&code);
+        assert_eq!(forest.loops().len(), 2);
+
+        let outer = &forest.loops()[0];
+        assert_eq!(outer.header, 1);
+        assert_eq!(outer.latches, vec![5]);
+        assert_eq!(outer.body.iter().collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(outer.exits, vec![(4, 6)]);
+        assert_eq!(forest.roots().collect::<Vec<_>>(), vec![0]);
+
+        let inner = &forest.loops()[1];
+        assert_eq!(inner.header, 2);
+        assert_eq!(inner.parent, Some(0));
+        assert_eq!(inner.body.iter().collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(inner.exits, vec![(3, 4)]);
+
+        let depths: Vec<usize> = (0..code.len()).map(|i| forest.depth(i)).collect();
+        assert_eq!(depths, vec![0, 1, 2, 2, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_unreachable_pred() {
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // 0: L0:
+        // 1: L1:
+        // 2: jump_if_zero read -> L0
+        // 3: return 0
+        // 4: jump L1
+        let code = code_to_graph(vec![
+            Stmt::Label(l0),
+            Stmt::Label(l1),
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Return(Int(0)),
+            Stmt::Jump(l1),
+        ]);
+
+        // 到達できないジャンプはループに含まれない
+        let forest = LoopForest::new(&code);
+        assert_eq!(forest.loops().len(), 1);
+        let l = &forest.loops()[0];
+        assert_eq!(l.latches, vec![2]);
+        assert_eq!(l.body.iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(forest.depth(4), 0);
+    }
+
+    #[test]
+    fn test_insert_preheaders() {
+        let i = Var::new("i");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let code = vec![
+            Stmt::Store(i, Int(0)),
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Label(l0),
+            Stmt::Store(i, Add(Box::new(LoadCopy(i)), Box::new(Int(1)))),
+            Stmt::JumpIfZero(Lt(Box::new(LoadCopy(i)), Box::new(Int(10))), l1),
+            Stmt::Jump(l0),
+            Stmt::Label(l1),
+            Stmt::Print(LoadCopy(i)),
+        ];
+
+        let code = insert_preheaders(code);
+        let preheader = match &code[2] {
+            Stmt::Label(label) => *label,
+            stmt => panic!("unexpected statement: {}", stmt),
+        };
+        assert_eq!(code[1], Stmt::JumpIfZero(Read, preheader));
+        assert_eq!(code[3], Stmt::Label(l0));
+        assert_eq!(code[6], Stmt::Jump(l0));
+
+        // ヘッダにはプリヘッダとループの中からだけ入る
+        let graph = code_to_graph(code);
+        let forest = LoopForest::new(&graph);
+        assert_eq!(forest.loops().len(), 1);
+        let mut preds: Vec<usize> = graph.pred_indexes(3).collect();
+        preds.sort_unstable();
+        assert_eq!(preds, vec![2, 6]);
+        assert_eq!(forest.depth(2), 0);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::ir::{Label, Stmt, Var};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LowerError {
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+}
+
+impl fmt::Display for LowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LowerError::BreakOutsideLoop => write!(f, "`break` outside of a loop"),
+            LowerError::ContinueOutsideLoop => write!(f, "`continue` outside of a loop"),
+        }
+    }
+}
+
+// ループの先頭と末尾のラベル
+struct Loop {
+    cond: Label,
+    end: Label,
+    // ループに入った時点で開いていたScopeの数
+    scope_depth: usize,
+}
+
+struct Lowering {
+    code: Vec<Stmt>,
+    loops: Vec<Loop>,
+    // 開いているScopeで宣言された変数
+    scopes: Vec<Vec<Var>>,
+}
+
+impl Lowering {
+    // ループから抜けるジャンプの前に、ループの中で開いたScopeを閉じる
+    fn jump_out_of_loop(&mut self, target: Label, scope_depth: usize) {
+        let vars: Vec<Var> = self.scopes[scope_depth..]
+            .iter()
+            .rev()
+            .flatten()
+            .copied()
+            .collect();
+        if !vars.is_empty() {
+            self.code.push(Stmt::EndScope(vars));
+        }
+
+        self.code.push(Stmt::Jump(target));
+    }
+
+    fn stmts(&mut self, stmts: Vec<Stmt>) -> Result<(), LowerError> {
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: Stmt) -> Result<(), LowerError> {
+        match stmt {
+            //   jump_if_zero cond -> L1
+            //   then
+            // L1:
+            Stmt::If(cond, then, els) if els.is_empty() => {
+                let end = Label::new();
+                self.code.push(Stmt::JumpIfZero(cond, end));
+                self.stmts(then)?;
+                self.code.push(Stmt::Label(end));
+            }
+            //   jump_if_zero cond -> L1
+            //   then
+            //   jump L2
+            // L1:
+            //   else
+            // L2:
+            Stmt::If(cond, then, els) => {
+                let else_label = Label::new();
+                let end = Label::new();
+                self.code.push(Stmt::JumpIfZero(cond, else_label));
+                self.stmts(then)?;
+                self.code.push(Stmt::Jump(end));
+                self.code.push(Stmt::Label(else_label));
+                self.stmts(els)?;
+                self.code.push(Stmt::Label(end));
+            }
+            // L1:
+            //   jump_if_zero cond -> L2
+            //   body
+            //   jump L1
+            // L2:
+            Stmt::While(cond, body) => {
+                let cond_label = Label::new();
+                let end = Label::new();
+                self.code.push(Stmt::Label(cond_label));
+                self.code.push(Stmt::JumpIfZero(cond, end));
+
+                self.loops.push(Loop {
+                    cond: cond_label,
+                    end,
+                    scope_depth: self.scopes.len(),
+                });
+                self.stmts(body)?;
+                self.loops.pop();
+
+                self.code.push(Stmt::Jump(cond_label));
+                self.code.push(Stmt::Label(end));
+            }
+            Stmt::Block(body) => self.stmts(body)?,
+            //   body
+            //   end_scope vars
+            Stmt::Scope(vars, body) => {
+                self.scopes.push(vars.clone());
+                self.stmts(body)?;
+                self.scopes.pop();
+
+                self.code.push(Stmt::EndScope(vars));
+            }
+            Stmt::Break => match self.loops.last() {
+                Some(l) => self.jump_out_of_loop(l.end, l.scope_depth),
+                None => return Err(LowerError::BreakOutsideLoop),
+            },
+            Stmt::Continue => match self.loops.last() {
+                Some(l) => self.jump_out_of_loop(l.cond, l.scope_depth),
+                None => return Err(LowerError::ContinueOutsideLoop),
+            },
+            stmt => self.code.push(stmt),
+        }
+
+        Ok(())
+    }
+}
+
+// 構造化された制御文をラベルとジャンプに変換する
+pub fn lower_control_flow(stmts: Vec<Stmt>) -> Result<Vec<Stmt>, LowerError> {
+    let mut lowering = Lowering {
+        code: Vec::new(),
+        loops: Vec::new(),
+        scopes: Vec::new(),
+    };
+    lowering.stmts(stmts)?;
+
+    Ok(lowering.code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::{Expr::*, Value, Var};
+    use crate::{ir_to_insts, VM};
+
+    #[test]
+    fn test_while() {
+        // i = 0; sum = 0
+        // while i < 10 { i = i + 1; if i % 2 == 0 { continue }; if i > 7 { break }; sum = sum + i }
+        // sum = 1 + 3 + 5 + 7 = 16
+        let i = Var::global("lower_i");
+        let sum = Var::global("lower_sum");
+        let body = vec![
+            Stmt::Store(i, Add(Box::new(LoadCopy(i)), Box::new(Int(1)))),
+            Stmt::If(
+                Eq(
+                    Box::new(Mod(Box::new(LoadCopy(i)), Box::new(Int(2)))),
+                    Box::new(Int(0)),
+                ),
+                vec![Stmt::Continue],
+                Vec::new(),
+            ),
+            Stmt::If(
+                Gt(Box::new(LoadCopy(i)), Box::new(Int(7))),
+                vec![Stmt::Break],
+                vec![Stmt::Store(
+                    sum,
+                    Add(Box::new(LoadCopy(sum)), Box::new(LoadCopy(i))),
+                )],
+            ),
+        ];
+        let code = vec![
+            Stmt::Store(i, Int(0)),
+            Stmt::Store(sum, Int(0)),
+            Stmt::While(Lt(Box::new(LoadCopy(i)), Box::new(Int(10))), body),
+        ];
+
+        let code = lower_control_flow(code).unwrap();
+        let mut vm = VM::new();
+        vm.run(&ir_to_insts(&code).unwrap()).unwrap();
+        assert_eq!(vm.global(sum), Some(Value::Int(16)));
+        assert_eq!(vm.global(i), Some(Value::Int(9)));
+    }
+
+    #[test]
+    fn test_break_outside_loop() {
+        let code = vec![Stmt::Block(vec![Stmt::Break])];
+        assert_eq!(lower_control_flow(code), Err(LowerError::BreakOutsideLoop));
+    }
+
+    #[test]
+    fn test_break_closes_scopes() {
+        let t = Var::new("t");
+        let code = vec![Stmt::While(
+            Int(1),
+            vec![Stmt::Scope(
+                vec![t],
+                vec![Stmt::Store(t, Int(1)), Stmt::Break],
+            )],
+        )];
+
+        let code = lower_control_flow(code).unwrap();
+        let break_index = code
+            .iter()
+            .position(|stmt| stmt == &Stmt::Store(t, Int(1)))
+            .unwrap()
+            + 1;
+        assert_eq!(code[break_index], Stmt::EndScope(vec![t]));
+        assert!(matches!(code[break_index + 1], Stmt::Jump(_)));
+    }
+}
@@ -1,21 +1,24 @@
-#![feature(box_syntax)]
-
-use opt_for_lang2::{ir, ir_to_insts, print_code, print_insts, Optimizer, VM};
+use opt_for_lang2::{
+    code_hoisting, common_subexpression_elimination, dead_store_elimination, ir, ir_to_insts,
+    print_code, print_insts, sparse_conditional_constant_propagation, Optimizer, VM,
+};
 
 fn main() {
     use ir::{Expr::*, Stmt::*};
 
     let l0 = ir::Label::new();
     let l1 = ir::Label::new();
+    let a = ir::Var::new("a");
+    let b = ir::Var::new("b");
     let code = vec![
-        Store(0, Int(30)),
+        Store(a, Int(30)),
         Jump(l0),
         Label(l1),
-        Expr(Add(box LoadCopy(0), box LoadCopy(1))),
-        Store(0, Int(5)),
+        Expr(Add(Box::new(LoadCopy(a)), Box::new(LoadCopy(b)))),
+        Store(a, Int(5)),
         Label(l0),
-        Store(1, Int(50)),
-        Print(LoadCopy(1)),
+        Store(b, Int(50)),
+        Print(LoadCopy(b)),
     ];
 
     let bbs = ir::stmts_to_bbs(code.clone());
@@ -24,18 +27,15 @@ fn main() {
         print_code(&bb.stmts);
     }
 
-    /*
-    let code = vec![
-        Store(0, Int(10)),
-        Store(0, Int(20)),
-        Store(1, LoadCopy(0)),
-        // Store(0, Int(20)),
-        Store(2, Add(box LoadCopy(1), box Int(5))),
-    ];
-    */
-
-    let optimizer = Optimizer::new(code);
-    let code = optimizer.optimize();
+    let code = sparse_conditional_constant_propagation(code);
+    let optimizer = Optimizer::new(code).debug(true);
+    let (code, warnings) = optimizer.optimize_with_warnings();
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    let code = common_subexpression_elimination(code);
+    let code = code_hoisting(code);
+    let code = dead_store_elimination(code);
 
     println!("----------------------------------------");
 
@@ -43,11 +43,19 @@ fn main() {
 
     println!("------------------------------------");
 
-    let insts = ir_to_insts(&code);
+    let insts = match ir_to_insts(&code) {
+        Ok(insts) => insts,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return;
+        }
+    };
     print_insts(&insts);
 
     println!("------------------------------------");
 
-    let vm = VM::new();
-    vm.run(&insts);
+    let mut vm = VM::new();
+    if let Err(trap) = vm.run(&insts) {
+        eprintln!("trap: {}", trap);
+    }
 }
@@ -0,0 +1,422 @@
+use crate::bbs_to_graph;
+use crate::bitset::BitSet;
+use crate::graph::DirectedGraph;
+use crate::ir::{stmts_to_bbs, BasicBlock, Expr, Label, Stmt, Value, Var};
+use crate::ssa::{construct_ssa, destruct_ssa};
+use std::collections::{HashMap, HashSet};
+
+// 変数の値の束。Undefined > Constant > Overdefinedの順に下がり、上には戻らない
+#[derive(Debug, Clone, Copy)]
+pub enum Lattice {
+    // まだ値が決まっていない。実行されない定義の値もこのまま残る
+    Undefined,
+    Constant(Value),
+    // 実行によって値が変わりうる
+    Overdefined,
+}
+
+impl Lattice {
+    pub fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Undefined, value) | (value, Lattice::Undefined) => value,
+            (Lattice::Constant(_), Lattice::Constant(_)) if self == other => self,
+            _ => Lattice::Overdefined,
+        }
+    }
+}
+
+// 浮動小数点数はビット列で比較する。NaNも同じ定数として扱える
+impl PartialEq for Lattice {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Lattice::Undefined, Lattice::Undefined)
+            | (Lattice::Overdefined, Lattice::Overdefined) => true,
+            (Lattice::Constant(Value::Float(a)), Lattice::Constant(Value::Float(b))) => {
+                a.to_bits() == b.to_bits()
+            }
+            (Lattice::Constant(a), Lattice::Constant(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+fn block_label(bb: &BasicBlock) -> Label {
+    match bb.stmts.first() {
+        Some(Stmt::Label(label)) => *label,
+        _ => panic!("a basic block must start with a label"),
+    }
+}
+
+fn substitute(expr: &mut Expr, values: &HashMap<Var, Lattice>) {
+    if let Expr::LoadCopy(var) = expr {
+        if let Some(Lattice::Constant(value)) = values.get(var) {
+            *expr = Expr::from(*value);
+        }
+        return;
+    }
+    for expr in expr.subexprs_mut() {
+        substitute(expr, values);
+    }
+}
+
+// SSA形式のコードでの、条件付き疎定数伝播
+struct Sccp<'a> {
+    graph: &'a DirectedGraph<BasicBlock>,
+    labels: HashMap<Label, usize>,
+    values: HashMap<Var, Lattice>,
+    // 変数を読む文の位置
+    uses: HashMap<Var, Vec<(usize, usize)>>,
+    executable_blocks: BitSet,
+    executable_edges: HashSet<(usize, usize)>,
+    flow_worklist: Vec<(Option<usize>, usize)>,
+    ssa_worklist: Vec<(usize, usize)>,
+}
+
+impl<'a> Sccp<'a> {
+    fn new(graph: &'a DirectedGraph<BasicBlock>) -> Self {
+        let mut labels = HashMap::new();
+        let mut values = HashMap::new();
+        let mut uses: HashMap<Var, Vec<(usize, usize)>> = HashMap::new();
+        for (b, bb) in graph.iter().enumerate() {
+            labels.insert(block_label(bb), b);
+            for (i, stmt) in bb.stmts.iter().enumerate() {
+                if let Stmt::Store(var, _) | Stmt::Phi(var, _) = stmt {
+                    if !var.is_global() {
+                        values.insert(*var, Lattice::Undefined);
+                    }
+                }
+                for var in stmt.used_vars() {
+                    uses.entry(var).or_default().push((b, i));
+                }
+            }
+        }
+
+        Self {
+            graph,
+            labels,
+            values,
+            uses,
+            executable_blocks: BitSet::new(),
+            executable_edges: HashSet::new(),
+            flow_worklist: vec![(None, 0)],
+            ssa_worklist: Vec::new(),
+        }
+    }
+
+    // グローバル変数と、定義されずに読まれる変数(引数など)は値が分からない
+    fn value(&self, var: Var) -> Lattice {
+        self.values
+            .get(&var)
+            .copied()
+            .unwrap_or(Lattice::Overdefined)
+    }
+
+    fn eval(&self, expr: &Expr) -> Lattice {
+        let mut undefined = false;
+        for var in expr.used_vars() {
+            match self.value(var) {
+                Lattice::Overdefined => return Lattice::Overdefined,
+                Lattice::Undefined => undefined = true,
+                Lattice::Constant(_) => {}
+            }
+        }
+        if undefined {
+            return Lattice::Undefined;
+        }
+
+        let mut expr = expr.clone();
+        substitute(&mut expr, &self.values);
+        match expr.eval_const() {
+            Some(value) => Lattice::Constant(value),
+            None => Lattice::Overdefined,
+        }
+    }
+
+    fn update(&mut self, var: Var, value: Lattice) {
+        let old = self.value(var);
+        let new = old.meet(value);
+        if new != old {
+            self.values.insert(var, new);
+            if let Some(uses) = self.uses.get(&var) {
+                self.ssa_worklist.extend(uses.iter().copied());
+            }
+        }
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize) {
+        self.flow_worklist.push((Some(from), to));
+    }
+
+    fn visit_stmt(&mut self, b: usize, i: usize) {
+        let graph = self.graph;
+        match &graph[b].stmts[i] {
+            Stmt::Phi(var, args) => {
+                let mut value = Lattice::Undefined;
+                for (label, arg) in args {
+                    if self.executable_edges.contains(&(self.labels[label], b)) {
+                        value = value.meet(self.value(*arg));
+                    }
+                }
+                self.update(*var, value);
+            }
+            Stmt::Store(var, expr) if !var.is_global() => {
+                let value = self.eval(expr);
+                self.update(*var, value);
+            }
+            Stmt::JumpIfZero(cond, label) => match self.eval(cond) {
+                Lattice::Undefined => {}
+                Lattice::Constant(value) if value.is_zero() => self.add_edge(b, self.labels[label]),
+                Lattice::Constant(_) if b + 1 < graph.len() => self.add_edge(b, b + 1),
+                Lattice::Constant(_) => {}
+                Lattice::Overdefined => {
+                    for succ in graph.succ_indexes(b) {
+                        self.add_edge(b, succ);
+                    }
+                }
+            },
+            Stmt::Switch(expr, cases, default) => match self.eval(expr) {
+                Lattice::Undefined => {}
+                Lattice::Constant(Value::Int(n)) => {
+                    let target = cases
+                        .iter()
+                        .find(|(value, _)| *value == n)
+                        .map_or(default, |(_, label)| label);
+                    self.add_edge(b, self.labels[target]);
+                }
+                _ => {
+                    for succ in graph.succ_indexes(b) {
+                        self.add_edge(b, succ);
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // 条件によらない後続への辺を実行されうるものにする
+    fn visit_terminator(&mut self, b: usize) {
+        let graph = self.graph;
+        match graph[b].stmts.last() {
+            Some(Stmt::JumpIfZero(_, _)) | Some(Stmt::Switch(_, _, _)) => {}
+            _ => {
+                for succ in graph.succ_indexes(b) {
+                    self.add_edge(b, succ);
+                }
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        loop {
+            if let Some((from, to)) = self.flow_worklist.pop() {
+                if let Some(from) = from {
+                    if !self.executable_edges.insert((from, to)) {
+                        continue;
+                    }
+                }
+
+                // 初めて実行される場合は全ての文を、そうでなければφだけを評価し直す
+                let first = self.executable_blocks.insert(to);
+                for i in 0..self.graph[to].stmts.len() {
+                    if first || matches!(self.graph[to].stmts[i], Stmt::Phi(_, _)) {
+                        self.visit_stmt(to, i);
+                    }
+                }
+                if first {
+                    self.visit_terminator(to);
+                }
+            } else if let Some((b, i)) = self.ssa_worklist.pop() {
+                if self.executable_blocks.contains(b) {
+                    self.visit_stmt(b, i);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+// 定数になった変数を読む式と定義を定数に、定数の条件の分岐をJumpに書き換える
+// 条件が0でない分岐は次のブロックに落ちるだけなので削除する
+fn rewrite(mut stmt: Stmt, values: &HashMap<Var, Lattice>) -> Option<Stmt> {
+    for expr in stmt.exprs_mut() {
+        substitute(expr, values);
+    }
+
+    match &stmt {
+        Stmt::Store(var, _) | Stmt::Phi(var, _) => match values.get(var) {
+            Some(Lattice::Constant(value)) => Some(Stmt::Store(*var, Expr::from(*value))),
+            _ => Some(stmt),
+        },
+        Stmt::JumpIfZero(cond, label) => match cond.eval_const() {
+            Some(value) if value.is_zero() => Some(Stmt::Jump(*label)),
+            Some(_) => None,
+            None => Some(stmt),
+        },
+        Stmt::Switch(expr, cases, default) => match expr.eval_const() {
+            Some(Value::Int(n)) => {
+                let target = cases
+                    .iter()
+                    .find(|(value, _)| *value == n)
+                    .map_or(*default, |(_, label)| *label);
+                Some(Stmt::Jump(target))
+            }
+            _ => Some(stmt),
+        },
+        _ => Some(stmt),
+    }
+}
+
+// 条件付き疎定数伝播(SCCP)
+// 実行されうる辺だけを辿りながら変数の値を求めるので、全ての経路で同じ定数を代入した変数や、
+// 定数の条件で選ばれない側でだけ値が変わる変数も定数として伝播できる
+// 定数の条件の分岐はJumpにするか削除し、実行されなくなったブロックを削除する
+// lower_control_flow()で変換した後のコードを対象にする
+pub fn sparse_conditional_constant_propagation(code: Vec<Stmt>) -> Vec<Stmt> {
+    let graph = bbs_to_graph(stmts_to_bbs(construct_ssa(code)));
+    if graph.len() == 0 {
+        return Vec::new();
+    }
+
+    let mut sccp = Sccp::new(&graph);
+    sccp.run();
+
+    let mut bbs: Vec<BasicBlock> = graph.iter().cloned().collect();
+    for bb in &mut bbs {
+        let stmts = std::mem::take(&mut bb.stmts);
+        bb.stmts = stmts
+            .into_iter()
+            .filter_map(|stmt| rewrite(stmt, &sccp.values))
+            .collect();
+    }
+
+    // 書き換えた後のグラフで、入口から到達できるブロックだけを残す
+    let graph = bbs_to_graph(bbs);
+    let reachable: BitSet = graph.reverse_post_order(0).into_iter().collect();
+    let labels: HashMap<Label, usize> = graph
+        .iter()
+        .enumerate()
+        .map(|(b, bb)| (block_label(bb), b))
+        .collect();
+
+    let mut code = Vec::new();
+    for b in 0..graph.len() {
+        if !reachable.contains(b) {
+            continue;
+        }
+
+        let preds: HashSet<usize> = graph
+            .pred_indexes(b)
+            .filter(|pred| reachable.contains(*pred))
+            .collect();
+        for stmt in &graph[b].stmts {
+            match stmt {
+                // 削除した辺から来る値は選ばれない
+                Stmt::Phi(var, args) => code.push(Stmt::Phi(
+                    *var,
+                    args.iter()
+                        .filter(|(label, _)| preds.contains(&labels[label]))
+                        .cloned()
+                        .collect(),
+                )),
+                stmt => code.push(stmt.clone()),
+            }
+        }
+    }
+
+    destruct_ssa(code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::Expr::*;
+    use crate::ir_to_insts;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_same_constant_on_both_arms() {
+        let x = Var::new("x");
+        let i = Var::new("i");
+        let result = Var::global("sccp");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        let l2 = Label::new();
+        let l3 = Label::new();
+        let code = vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Store(x, Int(1)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Store(x, Int(1)),
+            Stmt::Label(l1),
+            Stmt::Print(LoadCopy(x)),
+            // ループで値が変わる変数は定数にならない
+            Stmt::Store(i, Int(0)),
+            Stmt::Label(l2),
+            Stmt::Store(i, Add(Box::new(LoadCopy(i)), Box::new(LoadCopy(x)))),
+            Stmt::JumpIfZero(Lt(Box::new(Int(4)), Box::new(LoadCopy(i))), l2),
+            Stmt::Label(l3),
+            Stmt::Store(result, LoadCopy(i)),
+        ];
+
+        let code = sparse_conditional_constant_propagation(code);
+        assert!(code.contains(&Stmt::Print(Int(1))));
+        assert!(code
+            .iter()
+            .any(|stmt| matches!(stmt, Stmt::JumpIfZero(Lt(_, _), _))));
+
+        let mut vm = VM::new();
+        vm.set_input(vec![0]);
+        vm.run(&ir_to_insts(&code).unwrap()).unwrap();
+        assert_eq!(vm.global(result), Some(Value::Int(5)));
+    }
+
+    #[test]
+    fn test_constant_branch() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // yは実行されない分岐でだけ書き換えられる
+        let code = vec![
+            Stmt::Store(x, Int(0)),
+            Stmt::Store(y, Int(3)),
+            Stmt::JumpIfZero(LoadCopy(x), l0),
+            Stmt::Store(y, Read),
+            Stmt::Print(Int(1)),
+            Stmt::Label(l0),
+            Stmt::JumpIfZero(Eq(Box::new(LoadCopy(y)), Box::new(Int(3))), l1),
+            Stmt::Print(LoadCopy(y)),
+            Stmt::Label(l1),
+            Stmt::Return(Int(0)),
+        ];
+
+        let code = sparse_conditional_constant_propagation(code);
+        assert!(!code
+            .iter()
+            .any(|stmt| matches!(stmt, Stmt::JumpIfZero(_, _) | Stmt::Print(Int(1)))));
+        assert!(!code.iter().any(|stmt| matches!(stmt, Stmt::Store(_, Read))));
+        assert!(code.contains(&Stmt::Print(Int(3))));
+    }
+
+    #[test]
+    fn test_variable_count() {
+        let x = Var::new("x");
+        let result = Var::global("sccp_count");
+        let mut code = Vec::new();
+        for _ in 0..60 {
+            code.push(Stmt::Store(x, Read));
+            code.push(Stmt::Print(LoadCopy(x)));
+        }
+        code.push(Stmt::Store(result, LoadCopy(x)));
+        assert!(ir_to_insts(&code).is_ok());
+
+        // SSA形式を経由しても、命令列にできるコードのまま返す
+        let code = sparse_conditional_constant_propagation(code);
+        let mut vm = VM::new();
+        vm.set_input(1..=60);
+        vm.run(&ir_to_insts(&code).unwrap()).unwrap();
+        assert_eq!(vm.global(result), Some(Value::Int(60)));
+    }
+}
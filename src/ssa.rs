@@ -0,0 +1,681 @@
+use crate::bbs_to_graph;
+use crate::bitset::BitSet;
+use crate::code_to_graph;
+use crate::dominators::Dominators;
+use crate::graph::DirectedGraph;
+use crate::ir::{stmts_to_bbs, BasicBlock, Expr, Label, Stmt, Var};
+use crate::liveness::Liveness;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+fn block_label(bb: &BasicBlock) -> Label {
+    match bb.stmts.first() {
+        Some(Stmt::Label(label)) => *label,
+        _ => panic!("a basic block must start with a label"),
+    }
+}
+
+// 変数の読み出しを、その時点の最新の版に書き換える
+fn rename_expr(expr: &mut Expr, stacks: &HashMap<Var, Vec<Var>>) {
+    if let Expr::LoadCopy(var) = expr {
+        if let Some(top) = stacks.get(var).and_then(|stack| stack.last()) {
+            *var = *top;
+        }
+        return;
+    }
+    for expr in expr.subexprs_mut() {
+        rename_expr(expr, stacks);
+    }
+}
+
+enum Visit {
+    Enter(usize),
+    // ブロックで版を追加した変数
+    Exit(Vec<Var>),
+}
+
+// 刈り込み済みSSA形式に変換する
+// 支配辺境の合流点のうち、変数が生きているところにだけφを置く
+// 定義される前に読まれる変数(引数など)は、元の変数を入口での版として使う
+// lower_control_flow()で変換した後のコードを対象にする。入口から到達できないブロックは削除する
+pub fn construct_ssa(code: Vec<Stmt>) -> Vec<Stmt> {
+    let mut bbs = stmts_to_bbs(code);
+    if bbs.is_empty() {
+        return Vec::new();
+    }
+
+    // 入口のブロックに入る辺があると、入口での値を渡すφの引数がないので、空のブロックを前に置く
+    if bbs_to_graph(bbs.clone()).pred_indexes(0).next().is_some() {
+        let mut entry = BasicBlock::new();
+        entry.stmts.push(Stmt::Label(Label::new()));
+        entry.stmts.push(Stmt::Jump(block_label(&bbs[0])));
+        bbs.insert(0, entry);
+    }
+
+    // ブロックの入口で生きている変数を求めるために、文のグラフでの位置を覚えておく
+    let mut starts = Vec::with_capacity(bbs.len());
+    let mut code = Vec::new();
+    for bb in &bbs {
+        starts.push(code.len());
+        code.extend(bb.stmts.iter().cloned());
+    }
+    let liveness = Liveness::new(&code_to_graph(code));
+
+    let mut graph = bbs_to_graph(bbs);
+    let dominators = Dominators::new(&graph, 0);
+
+    let mut def_blocks: BTreeMap<Var, BitSet> = BTreeMap::new();
+    for (b, bb) in graph.iter().enumerate() {
+        for stmt in &bb.stmts {
+            if let Stmt::Store(var, _) = stmt {
+                if !var.is_global() {
+                    def_blocks.entry(*var).or_default().insert(b);
+                }
+            }
+        }
+    }
+
+    // φを置くブロックと、その元の変数
+    let mut phis: Vec<Vec<Var>> = vec![Vec::new(); graph.len()];
+    for (var, blocks) in def_blocks {
+        let mut has_phi = BitSet::new();
+        let mut visited = blocks.clone();
+        let mut worklist: Vec<usize> = blocks.iter().collect();
+        while let Some(b) = worklist.pop() {
+            for join in dominators.frontier(b) {
+                // 生きていない変数の値は合流させなくてよい
+                if has_phi.contains(join) || !liveness.is_live_in(starts[join], var) {
+                    continue;
+                }
+                has_phi.insert(join);
+                phis[join].push(var);
+
+                // φも定義なので、その支配辺境にもφが要る
+                if visited.insert(join) {
+                    worklist.push(join);
+                }
+            }
+        }
+    }
+
+    // 支配木を辿りながら、定義ごとに新しい版を作って読み出しを書き換える
+    let mut phi_vars: Vec<Vec<Var>> = phis.clone();
+    let mut phi_args: Vec<Vec<Vec<(Label, Var)>>> = phis
+        .iter()
+        .map(|vars| vec![Vec::new(); vars.len()])
+        .collect();
+    let mut stacks: HashMap<Var, Vec<Var>> = HashMap::new();
+    let mut visits = vec![Visit::Enter(0)];
+    while let Some(visit) = visits.pop() {
+        let b = match visit {
+            Visit::Enter(b) => b,
+            Visit::Exit(pushed) => {
+                for var in pushed {
+                    stacks.get_mut(&var).unwrap().pop();
+                }
+                continue;
+            }
+        };
+
+        let mut pushed = Vec::new();
+        for (i, var) in phis[b].iter().enumerate() {
+            let version = var.new_version();
+            phi_vars[b][i] = version;
+            stacks.entry(*var).or_default().push(version);
+            pushed.push(*var);
+        }
+
+        for stmt in graph[b].stmts.iter_mut() {
+            for expr in stmt.exprs_mut() {
+                rename_expr(expr, &stacks);
+            }
+
+            match stmt {
+                Stmt::Store(var, _) if !var.is_global() => {
+                    let version = var.new_version();
+                    stacks.entry(*var).or_default().push(version);
+                    pushed.push(*var);
+                    *var = version;
+                }
+                // スコープの終わりは、その時点の版の値を使わないことを表す
+                Stmt::EndScope(vars) => {
+                    for var in vars.iter_mut() {
+                        if let Some(top) = stacks.get(var).and_then(|stack| stack.last()) {
+                            *var = *top;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let label = block_label(&graph[b]);
+        let succs: Vec<usize> = graph.succ_indexes(b).collect();
+        for succ in succs {
+            for (i, var) in phis[succ].iter().enumerate() {
+                let arg = match stacks.get(var).and_then(|stack| stack.last()) {
+                    Some(top) => *top,
+                    None => *var,
+                };
+                phi_args[succ][i].push((label, arg));
+            }
+        }
+
+        visits.push(Visit::Exit(pushed));
+        for child in dominators.children(b).iter().rev() {
+            visits.push(Visit::Enter(*child));
+        }
+    }
+
+    let mut new_code = Vec::new();
+    for (b, bb) in graph.into_iter().enumerate() {
+        if !dominators.is_reachable(b) {
+            continue;
+        }
+
+        let mut stmts = bb.stmts.into_iter();
+        new_code.extend(stmts.next());
+        for (var, mut args) in phi_vars[b].iter().zip(phi_args[b].drain(..)) {
+            args.sort_by_key(|(label, _)| label.as_usize());
+            new_code.push(Stmt::Phi(*var, args));
+        }
+        new_code.extend(stmts);
+    }
+
+    new_code
+}
+
+// 並列に行うコピーを、一つずつのStoreに並べる
+// 互いに値を入れ替えるコピーは、一時変数に退避して循環を切る
+fn sequentialize(copies: Vec<(Var, Var)>) -> Vec<Stmt> {
+    let mut pending: Vec<(Var, Var)> = copies
+        .into_iter()
+        .filter(|(dest, src)| dest != src)
+        .collect();
+    let mut stmts = Vec::with_capacity(pending.len());
+
+    while !pending.is_empty() {
+        // 他のコピーが読まない変数には、先に代入してよい
+        let ready = pending
+            .iter()
+            .position(|(dest, _)| pending.iter().all(|(_, src)| src != dest));
+        if let Some(i) = ready {
+            let (dest, src) = pending.remove(i);
+            stmts.push(Stmt::Store(dest, Expr::LoadCopy(src)));
+            continue;
+        }
+
+        let (dest, _) = pending[0];
+        let temp = Var::new("swap");
+        stmts.push(Stmt::Store(temp, Expr::LoadCopy(dest)));
+        for (_, src) in pending.iter_mut() {
+            if *src == dest {
+                *src = temp;
+            }
+        }
+    }
+
+    stmts
+}
+
+// φを先行ブロックの末尾でのコピーに変換して、SSA形式から戻す
+// 一つのブロックのφは同時に行うコピーとして並べるので、φ同士で値を入れ替えても壊れない
+// 後続が複数ある先行ブロックでは、他の経路で生きている変数を書き換えないように辺を分割してコピーを置く
+// 最後に、干渉しない版を元の変数にまとめる
+pub fn destruct_ssa(code: Vec<Stmt>) -> Vec<Stmt> {
+    let graph: DirectedGraph<BasicBlock> = bbs_to_graph(stmts_to_bbs(code));
+    let labels: HashMap<Label, usize> = graph
+        .iter()
+        .enumerate()
+        .map(|(b, bb)| (block_label(bb), b))
+        .collect();
+
+    let mut bbs: Vec<BasicBlock> = graph.iter().cloned().collect();
+
+    // 先行ブロックと後続ブロックごとの、同時に行うコピー
+    let mut copies: BTreeMap<(usize, usize), Vec<(Var, Var)>> = BTreeMap::new();
+    for (b, bb) in bbs.iter_mut().enumerate() {
+        bb.stmts.retain(|stmt| match stmt {
+            Stmt::Phi(var, args) => {
+                for (label, arg) in args {
+                    copies
+                        .entry((labels[label], b))
+                        .or_default()
+                        .push((*var, *arg));
+                }
+                false
+            }
+            _ => true,
+        });
+    }
+
+    // 分割した辺に置くブロック。元の先行ブロックの直後に置く
+    let mut edge_blocks: Vec<Vec<(usize, BasicBlock)>> = vec![Vec::new(); bbs.len()];
+    for ((pred, succ), copies) in copies {
+        let stmts = sequentialize(copies);
+        let bb = &mut bbs[pred];
+        if let Some(Stmt::Jump(_)) = bb.stmts.last() {
+            let last = bb.stmts.len() - 1;
+            bb.stmts.splice(last..last, stmts);
+            continue;
+        }
+
+        let label = Label::new();
+        let succ_label = block_label(&graph[succ]);
+        if let Some(last) = bb.stmts.last_mut() {
+            for target in last.jump_targets_mut() {
+                if *target == succ_label {
+                    *target = label;
+                }
+            }
+        }
+
+        let mut edge = BasicBlock::new();
+        edge.stmts.push(Stmt::Label(label));
+        edge.stmts.extend(stmts);
+        edge.stmts.push(Stmt::Jump(succ_label));
+        edge_blocks[pred].push((succ, edge));
+    }
+
+    // 分割した辺のブロックに落ちないように、先行ブロックからの落ち先を明示する
+    let mut end = None;
+    let len = bbs.len();
+    let mut new_code = Vec::new();
+    for (b, (mut bb, mut edges)) in bbs.into_iter().zip(edge_blocks).enumerate() {
+        let falls_through = bb.stmts.last().is_none_or(Stmt::falls_through);
+        if falls_through && !edges.is_empty() {
+            match edges.iter().position(|(succ, _)| *succ == b + 1) {
+                Some(i) => {
+                    let edge = edges.remove(i);
+                    edges.insert(0, edge);
+                }
+                None if b + 1 < len => {
+                    bb.stmts.push(Stmt::Jump(block_label(&graph[b + 1])));
+                }
+                None => {
+                    bb.stmts
+                        .push(Stmt::Jump(*end.get_or_insert_with(Label::new)));
+                }
+            }
+        }
+
+        new_code.extend(bb.stmts);
+        for (_, edge) in edges {
+            new_code.extend(edge.stmts);
+        }
+    }
+    if let Some(end) = end {
+        new_code.push(Stmt::Label(end));
+    }
+
+    coalesce_versions(new_code)
+}
+
+// 変数を書き換える。代入先とスコープの終わりの変数も書き換える
+fn rename_vars(stmt: &mut Stmt, names: &HashMap<Var, Var>) {
+    for expr in stmt.exprs_mut() {
+        rename_loads(expr, names);
+    }
+    match stmt {
+        Stmt::Store(var, _) => {
+            if let Some(name) = names.get(var) {
+                *var = *name;
+            }
+        }
+        Stmt::EndScope(vars) => {
+            for var in vars.iter_mut() {
+                if let Some(name) = names.get(var) {
+                    *var = *name;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn rename_loads(expr: &mut Expr, names: &HashMap<Var, Var>) {
+    if let Expr::LoadCopy(var) = expr {
+        if let Some(name) = names.get(var) {
+            *var = *name;
+        }
+        return;
+    }
+    for expr in expr.subexprs_mut() {
+        rename_loads(expr, names);
+    }
+}
+
+// 同じ名前の変数の版のうち、生存区間が重ならないものを一つの変数にまとめる
+// 版ごとに別の変数のままだと、VMの変数の数が版の数だけ増える
+fn coalesce_versions(code: Vec<Stmt>) -> Vec<Stmt> {
+    let graph = code_to_graph(code);
+    if graph.len() == 0 {
+        return Vec::new();
+    }
+    let liveness = Liveness::new(&graph);
+
+    // 代入する変数は、その直後で生きている変数と干渉する。コピーの元とは値が同じなので干渉しない
+    // スコープの終わりの変数も、その後で読まれないと見なされるので、生きている変数と干渉させる
+    let mut interference: HashSet<(Var, Var)> = HashSet::new();
+    let mut interfere = |a: Var, b: Var| {
+        if a != b {
+            interference.insert((a, b));
+            interference.insert((b, a));
+        }
+    };
+    // 入口で生きている変数(引数など)はそれぞれ別の値を持つ
+    let entry = liveness.live_in(0);
+    for a in &entry {
+        for b in &entry {
+            interfere(*a, *b);
+        }
+    }
+
+    let mut vars: BTreeSet<Var> = BTreeSet::new();
+    for (i, stmt) in graph.iter().enumerate() {
+        let (defined, src) = match stmt {
+            Stmt::Store(var, Expr::LoadCopy(src)) => (vec![*var], Some(*src)),
+            Stmt::Store(var, _) => (vec![*var], None),
+            Stmt::EndScope(vars) => (vars.clone(), None),
+            _ => (Vec::new(), None),
+        };
+        for var in defined.iter().filter(|var| !var.is_global()) {
+            for live in liveness.live_out(i) {
+                if Some(live) != src {
+                    interfere(*var, live);
+                }
+            }
+        }
+        vars.extend(
+            stmt.used_vars()
+                .into_iter()
+                .chain(defined)
+                .filter(|var| !var.is_global()),
+        );
+    }
+
+    // 先に作られた版から順に、干渉しない組に入れる
+    let mut groups: Vec<Vec<Var>> = Vec::new();
+    let mut base_groups: HashMap<String, Vec<usize>> = HashMap::new();
+    for var in vars {
+        let candidates = base_groups.entry(var.base_name()).or_default();
+        let group = candidates.iter().copied().find(|g| {
+            groups[*g]
+                .iter()
+                .all(|member| !interference.contains(&(var, *member)))
+        });
+        match group {
+            Some(g) => groups[g].push(var),
+            None => {
+                candidates.push(groups.len());
+                groups.push(vec![var]);
+            }
+        }
+    }
+
+    // 入口での値を持つ変数があれば、組をその変数にまとめる
+    let mut names = HashMap::new();
+    for group in groups {
+        let name = group
+            .iter()
+            .copied()
+            .find(|var| entry.contains(var))
+            .unwrap_or(group[0]);
+        for var in group {
+            names.insert(var, name);
+        }
+    }
+
+    let mut new_code = Vec::with_capacity(graph.len());
+    for mut stmt in graph.into_iter() {
+        rename_vars(&mut stmt, &names);
+        match &stmt {
+            Stmt::Store(var, Expr::LoadCopy(src)) if var == src => {}
+            _ => new_code.push(stmt),
+        }
+    }
+
+    new_code
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::Expr::*;
+    use crate::ir::Value;
+    use crate::ir_to_insts;
+    use crate::vm::{CodegenError, VM};
+    use std::collections::HashSet;
+
+    fn run(code: &[Stmt], result: Var) -> Option<Value> {
+        let mut vm = VM::new();
+        vm.run(&ir_to_insts(code).unwrap()).unwrap();
+        vm.global(result)
+    }
+
+    #[test]
+    fn test_construct_ssa() {
+        let i = Var::new("i");
+        let sum = Var::new("sum");
+        let result = Var::global("ssa_sum");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // 0から9までの和
+        let code = vec![
+            Stmt::Store(i, Int(0)),
+            Stmt::Store(sum, Int(0)),
+            Stmt::Label(l0),
+            Stmt::JumpIfZero(Lt(Box::new(LoadCopy(i)), Box::new(Int(10))), l1),
+            Stmt::Store(sum, Add(Box::new(LoadCopy(sum)), Box::new(LoadCopy(i)))),
+            Stmt::Store(i, Add(Box::new(LoadCopy(i)), Box::new(Int(1)))),
+            Stmt::Jump(l0),
+            Stmt::Label(l1),
+            Stmt::Store(result, LoadCopy(sum)),
+        ];
+
+        let ssa = construct_ssa(code.clone());
+
+        // 変数はそれぞれ一度だけ代入される
+        let mut defined = HashSet::new();
+        for stmt in &ssa {
+            if let Stmt::Store(var, _) | Stmt::Phi(var, _) = stmt {
+                assert!(var.is_global() || defined.insert(*var), "{}", var);
+            }
+        }
+
+        // ループの先頭でiとsumが合流する
+        let header = ssa
+            .iter()
+            .position(|stmt| *stmt == Stmt::Label(l0))
+            .unwrap();
+        let phis: Vec<&Stmt> = ssa[header + 1..]
+            .iter()
+            .take_while(|stmt| matches!(stmt, Stmt::Phi(_, _)))
+            .collect();
+        assert_eq!(phis.len(), 2);
+        for phi in phis {
+            match phi {
+                Stmt::Phi(_, args) => assert_eq!(args.len(), 2),
+                _ => unreachable!(),
+            }
+        }
+
+        let code = destruct_ssa(ssa);
+        assert_eq!(run(&code, result), Some(Value::Int(45)));
+    }
+
+    #[test]
+    fn test_pruned() {
+        let x = Var::new("x");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // 合流点の後でxが読まれないので、φは要らない
+        let code = vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Store(x, Int(1)),
+            Stmt::Print(LoadCopy(x)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Store(x, Int(2)),
+            Stmt::Print(LoadCopy(x)),
+            Stmt::Label(l1),
+            Stmt::Return(Int(0)),
+        ];
+
+        let ssa = construct_ssa(code);
+        assert!(!ssa.iter().any(|stmt| matches!(stmt, Stmt::Phi(_, _))));
+        // 分岐先のブロックも残る
+        assert!(ssa.contains(&Stmt::Label(l0)));
+        assert_eq!(
+            ssa.iter()
+                .filter(|stmt| matches!(stmt, Stmt::Print(_)))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_branch_at_entry() {
+        let x = Var::new("x");
+        let result = Var::global("ssa_entry");
+        let l0 = Label::new();
+        let l1 = Label::new();
+        // 入口のブロックが分岐で終わり、合流点でxが読まれる
+        let code = vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Store(x, Int(1)),
+            Stmt::Jump(l1),
+            Stmt::Label(l0),
+            Stmt::Store(x, Int(2)),
+            Stmt::Label(l1),
+            Stmt::Store(result, LoadCopy(x)),
+        ];
+
+        let ssa = construct_ssa(code);
+        assert_eq!(
+            ssa.iter()
+                .filter(|stmt| matches!(stmt, Stmt::Phi(_, _)))
+                .count(),
+            1
+        );
+
+        // φ関数が残ったコードは命令列にできない
+        assert!(matches!(
+            ir_to_insts(&ssa),
+            Err(CodegenError::UnexpectedPhi(_))
+        ));
+
+        let program = ir_to_insts(&destruct_ssa(ssa)).unwrap();
+        for (input, expected) in [(0, 2), (1, 1)] {
+            let mut vm = VM::new();
+            vm.set_input(vec![input]);
+            vm.run(&program).unwrap();
+            assert_eq!(vm.global(result), Some(Value::Int(expected)));
+        }
+    }
+
+    #[test]
+    fn test_swap() {
+        let a = Var::new("a");
+        let b = Var::new("b");
+        let (a0, a1, b0, b1) = (
+            a.new_version(),
+            a.new_version(),
+            b.new_version(),
+            b.new_version(),
+        );
+        let i0 = Var::new("i");
+        let (i1, i2) = (i0.new_version(), i0.new_version());
+        let result = Var::global("ssa_swap");
+        let (l0, l1, l2) = (Label::new(), Label::new(), Label::new());
+
+        // 3回入れ替えるループ。a1とb1は互いのφの引数になる
+        // ループの先頭への辺は条件分岐からなので、分割が必要になる
+        let ssa = vec![
+            Stmt::Label(l0),
+            Stmt::Store(a0, Int(1)),
+            Stmt::Store(b0, Int(2)),
+            Stmt::Store(i0, Int(0)),
+            Stmt::Jump(l1),
+            Stmt::Label(l1),
+            Stmt::Phi(a1, vec![(l0, a0), (l1, b1)]),
+            Stmt::Phi(b1, vec![(l0, b0), (l1, a1)]),
+            Stmt::Phi(i1, vec![(l0, i0), (l1, i2)]),
+            Stmt::Store(i2, Add(Box::new(LoadCopy(i1)), Box::new(Int(1)))),
+            Stmt::JumpIfZero(Lt(Box::new(Int(2)), Box::new(LoadCopy(i2))), l1),
+            Stmt::Label(l2),
+            // 最後に入れ替えた後のaとbではなく、φの値を読む
+            Stmt::Store(
+                result,
+                Add(
+                    Box::new(Mul(Box::new(LoadCopy(a1)), Box::new(Int(10)))),
+                    Box::new(LoadCopy(b1)),
+                ),
+            ),
+        ];
+
+        let code = destruct_ssa(ssa);
+        assert!(!code.iter().any(|stmt| matches!(stmt, Stmt::Phi(_, _))));
+        // a, bは(1, 2), (2, 1), (1, 2)と変わる
+        assert_eq!(run(&code, result), Some(Value::Int(12)));
+    }
+
+    #[test]
+    fn test_lost_copy() {
+        let x = Var::new("x");
+        let (x1, x2, x3) = (x.new_version(), x.new_version(), x.new_version());
+        let result = Var::global("ssa_lost_copy");
+        let (l0, l1, l2) = (Label::new(), Label::new(), Label::new());
+
+        // ループの後でφの値x2を読むので、ループの末尾でx2に代入すると値が失われる
+        let ssa = vec![
+            Stmt::Label(l0),
+            Stmt::Store(x1, Int(1)),
+            Stmt::Jump(l1),
+            Stmt::Label(l1),
+            Stmt::Phi(x2, vec![(l0, x1), (l1, x3)]),
+            Stmt::Store(x3, Add(Box::new(LoadCopy(x2)), Box::new(Int(1)))),
+            Stmt::JumpIfZero(Lt(Box::new(Int(2)), Box::new(LoadCopy(x3))), l1),
+            Stmt::Label(l2),
+            Stmt::Store(result, LoadCopy(x2)),
+        ];
+
+        assert_eq!(run(&destruct_ssa(ssa), result), Some(Value::Int(2)));
+    }
+
+    #[test]
+    fn test_coalesce_versions() {
+        let x = Var::new("x");
+        let result = Var::global("ssa_coalesce");
+        let mut code = Vec::new();
+        // VMの変数の数より多く代入する
+        for _ in 0..60 {
+            code.push(Stmt::Store(x, Read));
+            code.push(Stmt::Print(LoadCopy(x)));
+        }
+        code.push(Stmt::Store(result, LoadCopy(x)));
+        assert!(ir_to_insts(&code).is_ok());
+
+        // 版は全て一つの変数にまとまる
+        let code = destruct_ssa(construct_ssa(code));
+        let vars: HashSet<Var> = code.iter().flat_map(Stmt::used_vars).collect();
+        assert_eq!(vars.len(), 1);
+
+        let program = ir_to_insts(&code).unwrap();
+        let mut vm = VM::new();
+        vm.set_input(1..=60);
+        vm.run(&program).unwrap();
+        assert_eq!(vm.global(result), Some(Value::Int(60)));
+
+        // 入口での値を持つ変数(引数など)には、その変数のまままとめる
+        let n = Var::new("n");
+        let l0 = Label::new();
+        let code = destruct_ssa(construct_ssa(vec![
+            Stmt::JumpIfZero(Read, l0),
+            Stmt::Store(n, Int(1)),
+            Stmt::Label(l0),
+            Stmt::Store(result, LoadCopy(n)),
+        ]));
+        assert!(code.contains(&Stmt::Store(n, Int(1))));
+        assert!(code.contains(&Stmt::Store(result, LoadCopy(n))));
+    }
+}
@@ -0,0 +1,378 @@
+use crate::ir::{BinOp, Expr, Function, Module, Stmt, Type, Var};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeError {
+    // エラーが起きた関数の名前
+    pub function: Option<String>,
+    // エラーが起きた文の位置
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "{} at {}: {}", name, self.index, self.message),
+            None => write!(f, "{}: {}", self.index, self.message),
+        }
+    }
+}
+
+struct TypeChecker<'a> {
+    module: Option<&'a Module>,
+    // 変数の型
+    // 変数は型が変わらないので、最初に代入される式の型から推論する
+    vars: HashMap<Var, Type>,
+    // 型が決まらなかった変数をi64として扱うかどうか
+    // VMの変数は0で初期化されるので、代入されない変数はi64になる
+    default_to_i64: bool,
+    ret: Option<Type>,
+}
+
+impl<'a> TypeChecker<'a> {
+    fn new(module: Option<&'a Module>) -> Self {
+        Self {
+            module,
+            vars: HashMap::new(),
+            default_to_i64: false,
+            ret: None,
+        }
+    }
+
+    fn binop_type(&self, op: BinOp, lhs: Type, rhs: Type) -> Result<Type, String> {
+        let ty = match (op, lhs, rhs) {
+            (BinOp::Add, _, _)
+            | (BinOp::Sub, _, _)
+            | (BinOp::Mul, _, _)
+            | (BinOp::Div, _, _)
+            | (BinOp::Mod, _, _)
+                if lhs == rhs && lhs != Type::Bool =>
+            {
+                lhs
+            }
+            (BinOp::Eq, _, _) | (BinOp::Ne, _, _) if lhs == rhs => Type::Bool,
+            (BinOp::Lt, _, _) | (BinOp::Le, _, _) | (BinOp::Gt, _, _) | (BinOp::Ge, _, _)
+                if lhs == rhs && lhs != Type::Bool =>
+            {
+                Type::Bool
+            }
+            (BinOp::And, Type::Bool, Type::Bool) | (BinOp::Or, Type::Bool, Type::Bool) => {
+                Type::Bool
+            }
+            (BinOp::And, Type::I64, Type::I64) | (BinOp::Or, Type::I64, Type::I64) => Type::I64,
+            (BinOp::BitAnd, Type::I64, Type::I64)
+            | (BinOp::BitOr, Type::I64, Type::I64)
+            | (BinOp::BitXor, Type::I64, Type::I64)
+            | (BinOp::Shl, Type::I64, Type::I64)
+            | (BinOp::Shr, Type::I64, Type::I64)
+            | (BinOp::UShr, Type::I64, Type::I64) => Type::I64,
+            _ => return Err(format!("cannot apply `{}` to {} and {}", op, lhs, rhs)),
+        };
+
+        Ok(ty)
+    }
+
+    fn call_type(&self, name: &str, args: &[Expr]) -> Result<Type, String> {
+        let func = match self.module.and_then(|module| module.function(name)) {
+            Some(func) => func,
+            None => return Err(format!("unknown function `{}`", name)),
+        };
+
+        if func.params.len() != args.len() {
+            return Err(format!(
+                "function `{}` takes {} arguments but {} were given",
+                name,
+                func.params.len(),
+                args.len()
+            ));
+        }
+
+        for (i, (arg, (_, param))) in args.iter().zip(&func.params).enumerate() {
+            let ty = self.expr_type(arg)?;
+            if ty != *param {
+                return Err(format!(
+                    "argument {} of `{}` must be {}, found {}",
+                    i, name, param, ty
+                ));
+            }
+        }
+
+        Ok(func.ret)
+    }
+
+    fn expr_type(&self, expr: &Expr) -> Result<Type, String> {
+        if let Some((op, lhs, rhs)) = expr.binop() {
+            let lhs = self.expr_type(lhs)?;
+            let rhs = self.expr_type(rhs)?;
+            return self.binop_type(op, lhs, rhs);
+        }
+
+        let ty = match expr {
+            Expr::Int(_) => Type::I64,
+            Expr::Bool(_) => Type::Bool,
+            Expr::Float(_) => Type::F64,
+            Expr::Not(expr) => match self.expr_type(expr)? {
+                Type::Bool => Type::Bool,
+                Type::I64 => Type::I64,
+                ty => return Err(format!("cannot apply `!` to {}", ty)),
+            },
+            Expr::Select(cond, then, els) => {
+                self.condition(cond)?;
+                let then = self.expr_type(then)?;
+                let els = self.expr_type(els)?;
+                if then != els {
+                    return Err(format!(
+                        "both values of select must have the same type, found {} and {}",
+                        then, els
+                    ));
+                }
+                then
+            }
+            Expr::Convert(ty, expr) => {
+                self.expr_type(expr)?;
+                *ty
+            }
+            Expr::LoadCopy(var) => match self.vars.get(var) {
+                Some(ty) => *ty,
+                None if self.default_to_i64 => Type::I64,
+                None => return Err(format!("the type of {} is unknown", var)),
+            },
+            Expr::Call(name, args) => self.call_type(name, args)?,
+            Expr::Load(addr) => {
+                self.expect(addr, Type::I64, "address")?;
+                Type::I64
+            }
+            Expr::Read => Type::I64,
+            Expr::Str(_) => return Err("string literals can only be printed".to_string()),
+            _ => unreachable!(),
+        };
+
+        Ok(ty)
+    }
+
+    fn expect(&self, expr: &Expr, expected: Type, what: &str) -> Result<(), String> {
+        let ty = self.expr_type(expr)?;
+        if ty != expected {
+            return Err(format!("{} must be {}, found {}", what, expected, ty));
+        }
+
+        Ok(())
+    }
+
+    fn condition(&self, expr: &Expr) -> Result<(), String> {
+        match self.expr_type(expr)? {
+            Type::I64 | Type::Bool => Ok(()),
+            ty => Err(format!("condition must be i64 or bool, found {}", ty)),
+        }
+    }
+
+    fn check_stmt(&self, stmt: &Stmt) -> Result<(), String> {
+        match stmt {
+            Stmt::Store(var, expr) => {
+                let ty = self.expr_type(expr)?;
+                match self.vars.get(var) {
+                    Some(var_ty) if *var_ty != ty => {
+                        return Err(format!("cannot store {} to {} of type {}", ty, var, var_ty));
+                    }
+                    _ => {}
+                }
+            }
+            Stmt::Print(Expr::Str(_)) | Stmt::PrintNoNewline(Expr::Str(_)) => {}
+            Stmt::Expr(expr) | Stmt::Print(expr) | Stmt::PrintNoNewline(expr) => {
+                self.expr_type(expr)?;
+            }
+            Stmt::JumpIfZero(expr, _) => self.condition(expr)?,
+            Stmt::Return(expr) => {
+                let ty = self.expr_type(expr)?;
+                match self.ret {
+                    Some(ret) if ret != ty => {
+                        return Err(format!("return type must be {}, found {}", ret, ty));
+                    }
+                    _ => {}
+                }
+            }
+            Stmt::StoreMem(addr, expr) => {
+                self.expect(addr, Type::I64, "address")?;
+                self.expect(expr, Type::I64, "value stored to memory")?;
+            }
+            Stmt::Switch(expr, _, _) => self.expect(expr, Type::I64, "switch value")?,
+            Stmt::Assert(expr, _) => self.condition(expr)?,
+            Stmt::If(cond, then, els) => {
+                self.condition(cond)?;
+                for stmt in then.iter().chain(els) {
+                    self.check_stmt(stmt)?;
+                }
+            }
+            Stmt::While(cond, body) => {
+                self.condition(cond)?;
+                for stmt in body {
+                    self.check_stmt(stmt)?;
+                }
+            }
+            Stmt::Block(body) | Stmt::Scope(_, body) => {
+                for stmt in body {
+                    self.check_stmt(stmt)?;
+                }
+            }
+            Stmt::Phi(var, args) => {
+                for (_, arg) in args {
+                    match (self.vars.get(var), self.vars.get(arg)) {
+                        (Some(var_ty), Some(ty)) if var_ty != ty => {
+                            return Err(format!(
+                                "cannot store {} to {} of type {}",
+                                ty, var, var_ty
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Stmt::Label(_)
+            | Stmt::Jump(_)
+            | Stmt::Trap(_)
+            | Stmt::Break
+            | Stmt::Continue
+            | Stmt::EndScope(_) => {}
+        }
+
+        Ok(())
+    }
+
+    // 型が決まった変数があればtrueを返す
+    fn infer_stmt(&mut self, stmt: &Stmt) -> bool {
+        match stmt {
+            Stmt::Store(var, expr) if !self.vars.contains_key(var) => match self.expr_type(expr) {
+                Ok(ty) => {
+                    self.vars.insert(*var, ty);
+                    true
+                }
+                Err(_) => false,
+            },
+            Stmt::Phi(var, args) if !self.vars.contains_key(var) => {
+                match args.iter().find_map(|(_, arg)| self.vars.get(arg).copied()) {
+                    Some(ty) => {
+                        self.vars.insert(*var, ty);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Stmt::If(_, then, els) => self.infer_stmts(then.iter().chain(els)),
+            Stmt::While(_, body) | Stmt::Block(body) | Stmt::Scope(_, body) => {
+                self.infer_stmts(body)
+            }
+            _ => false,
+        }
+    }
+
+    fn infer_stmts<'b>(&mut self, stmts: impl IntoIterator<Item = &'b Stmt>) -> bool {
+        let mut changed = false;
+        for stmt in stmts {
+            changed |= self.infer_stmt(stmt);
+        }
+
+        changed
+    }
+
+    // 変数に代入される式から変数の型を推論する
+    fn infer_vars(&mut self, code: &[Stmt]) {
+        while self.infer_stmts(code) {}
+
+        self.default_to_i64 = true;
+    }
+
+    fn check(&mut self, code: &[Stmt]) -> Result<(), (usize, String)> {
+        self.infer_vars(code);
+
+        for (i, stmt) in code.iter().enumerate() {
+            self.check_stmt(stmt).map_err(|message| (i, message))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn type_check(code: &[Stmt]) -> Result<(), TypeError> {
+    let mut checker = TypeChecker::new(None);
+    checker.check(code).map_err(|(index, message)| TypeError {
+        function: None,
+        index,
+        message,
+    })
+}
+
+fn check_function(module: &Module, func: &Function) -> Result<(), TypeError> {
+    let mut checker = TypeChecker::new(Some(module));
+    checker.ret = Some(func.ret);
+    for (var, ty) in &func.params {
+        checker.vars.insert(*var, *ty);
+    }
+
+    checker
+        .check(&func.body)
+        .map_err(|(index, message)| TypeError {
+            function: Some(func.name.clone()),
+            index,
+            message,
+        })
+}
+
+pub fn type_check_module(module: &Module) -> Result<(), TypeError> {
+    for func in &module.functions {
+        check_function(module, func)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::Expr::*;
+
+    #[test]
+    fn test_infer_vars() {
+        let x = Var::new("x");
+        let y = Var::new("y");
+        let code = vec![
+            Stmt::Store(y, Add(Box::new(LoadCopy(x)), Box::new(Float(1.5)))),
+            Stmt::Store(x, Float(2.0)),
+            Stmt::Print(Lt(Box::new(LoadCopy(y)), Box::new(LoadCopy(x)))),
+        ];
+        assert_eq!(type_check(&code), Ok(()));
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let x = Var::new("count");
+        let y = Var::new("y");
+        let code = vec![
+            Stmt::Store(x, Int(1)),
+            Stmt::Store(y, Mul(Box::new(LoadCopy(x)), Box::new(Float(2.0)))),
+        ];
+        let err = type_check(&code).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert_eq!(err.message, "cannot apply `*` to i64 and f64");
+
+        // 論理演算は整数どうしか真偽値どうしにだけ使える
+        let code = vec![Stmt::Print(Not(Box::new(And(
+            Box::new(Int(1)),
+            Box::new(Int(2)),
+        ))))];
+        assert_eq!(type_check(&code), Ok(()));
+        let code = vec![Stmt::Print(Or(Box::new(Int(1)), Box::new(Bool(false))))];
+        assert_eq!(
+            type_check(&code).unwrap_err().message,
+            "cannot apply `||` to i64 and bool"
+        );
+
+        let code = vec![Stmt::Store(x, Int(1)), Stmt::Store(x, Bool(true))];
+        let err = type_check(&code).unwrap_err();
+        assert_eq!(
+            err.message,
+            format!("cannot store bool to {} of type i64", x)
+        );
+    }
+}
@@ -1,160 +1,967 @@
-use crate::ir::{Expr, Stmt};
+use crate::ir::{BinOp, EvalError, Expr, Function, Label, Module, Stmt, Type, Value, Var};
+use crate::FormatIter;
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Read};
 
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Inst {
     Int(i64),
+    Bool(bool),
+    Float(f64),
     Add,
+    Sub,
     Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Not,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    UShr,
+    Select,
+    Convert(Type),
     Store(isize),
     LoadCopy(isize),
+    // Program::globalsの位置
+    StoreGlobal(usize),
+    LoadGlobal(usize),
     Jump(usize),
     JumpIfZero(usize),
     Call(usize),
+    // 呼び出す関数の位置と引数の数
+    CallFunc(usize, usize),
+    Return,
+    Pop,
+    Load,
+    StoreMem,
+    Read,
+    // 定数プールの文字列を出力する
+    PrintStr(usize),
+    PrintStrLn(usize),
+    // 最小の値と、その値から連続する値ごとの飛び先と、範囲外の場合の飛び先
+    JumpTable(i64, Vec<usize>, usize),
+    // 値でソートされた値と飛び先の組と、一致しない場合の飛び先
+    LookupSwitch(Vec<(i64, usize)>, usize),
+    // 定数プールのメッセージとともに実行を中断する
+    Assert(usize),
+    Trap(usize),
 }
 
-fn expr_to_insts(insts: &mut Vec<Inst>, expr: &Expr) {
-    match expr {
-        Expr::Int(n) => insts.push(Inst::Int(*n)),
-        Expr::Add(lhs, rhs) => {
-            expr_to_insts(insts, lhs);
-            expr_to_insts(insts, rhs);
-            insts.push(Inst::Add);
-        }
-        Expr::Mul(lhs, rhs) => {
-            expr_to_insts(insts, lhs);
-            expr_to_insts(insts, rhs);
-            insts.push(Inst::Mul);
+impl Inst {
+    pub fn binop(&self) -> Option<BinOp> {
+        let op = match self {
+            Inst::Add => BinOp::Add,
+            Inst::Sub => BinOp::Sub,
+            Inst::Mul => BinOp::Mul,
+            Inst::Div => BinOp::Div,
+            Inst::Mod => BinOp::Mod,
+            Inst::Eq => BinOp::Eq,
+            Inst::Ne => BinOp::Ne,
+            Inst::Lt => BinOp::Lt,
+            Inst::Le => BinOp::Le,
+            Inst::Gt => BinOp::Gt,
+            Inst::Ge => BinOp::Ge,
+            Inst::And => BinOp::And,
+            Inst::Or => BinOp::Or,
+            Inst::BitAnd => BinOp::BitAnd,
+            Inst::BitOr => BinOp::BitOr,
+            Inst::BitXor => BinOp::BitXor,
+            Inst::Shl => BinOp::Shl,
+            Inst::Shr => BinOp::Shr,
+            Inst::UShr => BinOp::UShr,
+            _ => return None,
+        };
+
+        Some(op)
+    }
+}
+
+// 値の範囲がこの倍数以下であればジャンプテーブルを使う
+const JUMP_TABLE_DENSITY: i128 = 2;
+
+// 組み込み関数の番号
+const PRINT: usize = 0;
+const PRINT_NO_NEWLINE: usize = 1;
+
+// VMで実行する命令列と、命令から参照される定数プール
+#[derive(Debug, Default)]
+pub struct Program {
+    pub insts: Vec<Inst>,
+    pub strings: Vec<String>,
+    // グローバル変数の名前。VMは名前で値を保持するので、別のProgramとも値を共有する
+    pub globals: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodegenError {
+    // 関数の変数がVMの変数の数に収まらない
+    TooManyVariables {
+        function: Option<String>,
+        needed: usize,
+    },
+    UnknownFunction(String),
+    // 関数呼び出しの引数の数が関数の引数の数と違う
+    WrongArgumentCount {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    MissingMain,
+    // 文字列は出力する文でしか使えない
+    UnexpectedString,
+    // lower_control_flow()で変換されていない文
+    NotLowered(String),
+    // destruct_ssa()で削除されていないφ関数
+    UnexpectedPhi(String),
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodegenError::TooManyVariables { function, needed } => {
+                if let Some(name) = function {
+                    write!(f, "{}: ", name)?;
+                }
+                write!(
+                    f,
+                    "{} variables are needed but the VM has only {}",
+                    needed, MAX_VARIABLES
+                )
+            }
+            CodegenError::UnknownFunction(name) => write!(f, "unknown function: {}", name),
+            CodegenError::WrongArgumentCount {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} takes {} arguments but {} were given",
+                function, expected, found
+            ),
+            CodegenError::MissingMain => write!(f, "function `main` is not defined"),
+            CodegenError::UnexpectedString => write!(f, "string literals can only be printed"),
+            CodegenError::NotLowered(stmt) => {
+                write!(f, "`{}` must be lowered by lower_control_flow()", stmt)
+            }
+            CodegenError::UnexpectedPhi(stmt) => {
+                write!(f, "`{}` must be removed by destruct_ssa()", stmt)
+            }
         }
-        Expr::LoadCopy(loc) => insts.push(Inst::LoadCopy(*loc)),
     }
 }
 
-fn stmt_to_insts(insts: &mut Vec<Inst>, labels: &mut HashMap<usize, usize>, stmt: &Stmt) {
-    match stmt {
-        Stmt::Expr(expr) => expr_to_insts(insts, expr),
-        Stmt::Store(loc, expr) => {
-            expr_to_insts(insts, expr);
-            insts.push(Inst::Store(*loc));
+// 命令列の生成中の状態
+#[derive(Default)]
+struct Codegen<'a> {
+    insts: Vec<Inst>,
+    // 生成中の関数の変数に割り当てたVMの変数の位置。引数は負の位置になる
+    slots: HashMap<Var, isize>,
+    // 次に割り当てる変数の位置
+    next_slot: usize,
+    globals: Vec<String>,
+    global_indexes: HashMap<Var, usize>,
+    strings: Vec<String>,
+    // 同じ文字列を一度だけプールに入れる
+    string_indexes: HashMap<&'a str, usize>,
+    // ラベル番号から命令の位置への対応
+    labels: HashMap<usize, usize>,
+    // 関数名から関数の先頭の命令の位置と引数の数への対応
+    functions: HashMap<&'a str, (usize, usize)>,
+    // 関数呼び出し命令の位置と呼び出す関数名
+    calls: Vec<(usize, &'a str)>,
+}
+
+impl<'a> Codegen<'a> {
+    fn binop(&mut self, lhs: &'a Expr, rhs: &'a Expr, op: Inst) -> Result<(), CodegenError> {
+        self.expr(lhs)?;
+        self.expr(rhs)?;
+        self.insts.push(op);
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &'a Expr) -> Result<(), CodegenError> {
+        match expr {
+            Expr::Int(n) => self.insts.push(Inst::Int(*n)),
+            Expr::Bool(b) => self.insts.push(Inst::Bool(*b)),
+            Expr::Float(x) => self.insts.push(Inst::Float(*x)),
+            Expr::Add(lhs, rhs) => self.binop(lhs, rhs, Inst::Add)?,
+            Expr::Sub(lhs, rhs) => self.binop(lhs, rhs, Inst::Sub)?,
+            Expr::Mul(lhs, rhs) => self.binop(lhs, rhs, Inst::Mul)?,
+            Expr::Div(lhs, rhs) => self.binop(lhs, rhs, Inst::Div)?,
+            Expr::Mod(lhs, rhs) => self.binop(lhs, rhs, Inst::Mod)?,
+            Expr::Eq(lhs, rhs) => self.binop(lhs, rhs, Inst::Eq)?,
+            Expr::Ne(lhs, rhs) => self.binop(lhs, rhs, Inst::Ne)?,
+            Expr::Lt(lhs, rhs) => self.binop(lhs, rhs, Inst::Lt)?,
+            Expr::Le(lhs, rhs) => self.binop(lhs, rhs, Inst::Le)?,
+            Expr::Gt(lhs, rhs) => self.binop(lhs, rhs, Inst::Gt)?,
+            Expr::Ge(lhs, rhs) => self.binop(lhs, rhs, Inst::Ge)?,
+            Expr::Not(expr) => {
+                self.expr(expr)?;
+                self.insts.push(Inst::Not);
+            }
+            Expr::And(lhs, rhs) => self.binop(lhs, rhs, Inst::And)?,
+            Expr::Or(lhs, rhs) => self.binop(lhs, rhs, Inst::Or)?,
+            Expr::BitAnd(lhs, rhs) => self.binop(lhs, rhs, Inst::BitAnd)?,
+            Expr::BitOr(lhs, rhs) => self.binop(lhs, rhs, Inst::BitOr)?,
+            Expr::BitXor(lhs, rhs) => self.binop(lhs, rhs, Inst::BitXor)?,
+            Expr::Shl(lhs, rhs) => self.binop(lhs, rhs, Inst::Shl)?,
+            Expr::Shr(lhs, rhs) => self.binop(lhs, rhs, Inst::Shr)?,
+            Expr::UShr(lhs, rhs) => self.binop(lhs, rhs, Inst::UShr)?,
+            Expr::Select(cond, then, els) => {
+                self.expr(cond)?;
+                self.expr(then)?;
+                self.expr(els)?;
+                self.insts.push(Inst::Select);
+            }
+            Expr::Convert(ty, expr) => {
+                self.expr(expr)?;
+                self.insts.push(Inst::Convert(*ty));
+            }
+            Expr::LoadCopy(var) if var.is_global() => {
+                let index = self.global(*var);
+                self.insts.push(Inst::LoadGlobal(index));
+            }
+            Expr::LoadCopy(var) => {
+                let loc = self.slot(*var);
+                self.insts.push(Inst::LoadCopy(loc));
+            }
+            Expr::Call(name, args) => {
+                for arg in args {
+                    self.expr(arg)?;
+                }
+
+                // 呼び出し先の位置は全ての関数を生成した後に埋める
+                self.calls.push((self.insts.len(), name));
+                self.insts.push(Inst::CallFunc(0, args.len()));
+            }
+            Expr::Load(addr) => {
+                self.expr(addr)?;
+                self.insts.push(Inst::Load);
+            }
+            Expr::Read => self.insts.push(Inst::Read),
+            Expr::Str(_) => return Err(CodegenError::UnexpectedString),
+        }
+
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &'a Stmt) -> Result<(), CodegenError> {
+        match stmt {
+            Stmt::Expr(expr) => {
+                self.expr(expr)?;
+                self.insts.push(Inst::Pop);
+            }
+            Stmt::Store(var, expr) if var.is_global() => {
+                self.expr(expr)?;
+                let index = self.global(*var);
+                self.insts.push(Inst::StoreGlobal(index));
+            }
+            Stmt::Store(var, expr) => {
+                self.expr(expr)?;
+                let loc = self.slot(*var);
+                self.insts.push(Inst::Store(loc));
+            }
+            Stmt::Label(label) => {
+                self.labels.insert(label.as_usize(), self.insts.len());
+            }
+            Stmt::Jump(label) => {
+                self.insts.push(Inst::Jump(label.as_usize()));
+            }
+            Stmt::JumpIfZero(expr, label) => {
+                self.expr(expr)?;
+                self.insts.push(Inst::JumpIfZero(label.as_usize()));
+            }
+            Stmt::Print(Expr::Str(s)) => {
+                let index = self.string(s);
+                self.insts.push(Inst::PrintStrLn(index));
+            }
+            Stmt::PrintNoNewline(Expr::Str(s)) => {
+                let index = self.string(s);
+                self.insts.push(Inst::PrintStr(index));
+            }
+            Stmt::Print(expr) => {
+                self.expr(expr)?;
+                self.insts.push(Inst::Call(PRINT));
+            }
+            Stmt::PrintNoNewline(expr) => {
+                self.expr(expr)?;
+                self.insts.push(Inst::Call(PRINT_NO_NEWLINE));
+            }
+            Stmt::Return(expr) => {
+                self.expr(expr)?;
+                self.insts.push(Inst::Return);
+            }
+            Stmt::StoreMem(addr, expr) => {
+                self.expr(addr)?;
+                self.expr(expr)?;
+                self.insts.push(Inst::StoreMem);
+            }
+            Stmt::Switch(expr, cases, default) => self.switch(expr, cases, *default)?,
+            Stmt::Assert(expr, message) => {
+                self.expr(expr)?;
+                let index = self.string(message);
+                self.insts.push(Inst::Assert(index));
+            }
+            Stmt::Trap(message) => {
+                let index = self.string(message);
+                self.insts.push(Inst::Trap(index));
+            }
+            // 変数の位置は再利用しないので、何も生成しない
+            Stmt::EndScope(_) => {}
+            Stmt::If(_, _, _)
+            | Stmt::While(_, _)
+            | Stmt::Block(_)
+            | Stmt::Scope(_, _)
+            | Stmt::Break
+            | Stmt::Continue => return Err(CodegenError::NotLowered(stmt.to_string())),
+            Stmt::Phi(_, _) => return Err(CodegenError::UnexpectedPhi(stmt.to_string())),
         }
-        Stmt::Label(label) => {
-            labels.insert(label.as_usize(), insts.len());
+
+        Ok(())
+    }
+
+    fn switch(
+        &mut self,
+        expr: &'a Expr,
+        cases: &[(i64, Label)],
+        default: Label,
+    ) -> Result<(), CodegenError> {
+        self.expr(expr)?;
+
+        // 同じ値が複数ある場合は最初のものを使う
+        let mut cases: Vec<(i64, usize)> = cases
+            .iter()
+            .map(|(value, label)| (*value, label.as_usize()))
+            .collect();
+        cases.sort_by_key(|(value, _)| *value);
+        cases.dedup_by_key(|(value, _)| *value);
+
+        let default = default.as_usize();
+        let (min, max) = match (cases.first(), cases.last()) {
+            (Some((min, _)), Some((max, _))) => (*min, *max),
+            _ => {
+                self.insts.push(Inst::LookupSwitch(Vec::new(), default));
+                return Ok(());
+            }
+        };
+
+        let range = max as i128 - min as i128 + 1;
+        if range <= cases.len() as i128 * JUMP_TABLE_DENSITY {
+            let mut table = vec![default; range as usize];
+            for (value, label) in cases {
+                table[(value as i128 - min as i128) as usize] = label;
+            }
+            self.insts.push(Inst::JumpTable(min, table, default));
+        } else {
+            self.insts.push(Inst::LookupSwitch(cases, default));
         }
-        Stmt::Jump(label) => {
-            insts.push(Inst::Jump(label.as_usize()));
+
+        Ok(())
+    }
+
+    // 変数に割り当てたVMの変数の位置を返す。初めて使われる変数には新しい位置を割り当てる
+    fn slot(&mut self, var: Var) -> isize {
+        if let Some(loc) = self.slots.get(&var) {
+            return *loc;
         }
-        Stmt::JumpIfZero(expr, label) => {
-            expr_to_insts(insts, expr);
-            insts.push(Inst::JumpIfZero(label.as_usize()));
+
+        let loc = self.next_slot as isize;
+        self.next_slot += 1;
+        self.slots.insert(var, loc);
+        loc
+    }
+
+    // グローバル変数の名前をProgram::globalsに入れて、その位置を返す
+    fn global(&mut self, var: Var) -> usize {
+        if let Some(index) = self.global_indexes.get(&var) {
+            return *index;
         }
-        Stmt::Print(expr) => {
-            expr_to_insts(insts, expr);
-            insts.push(Inst::Call(0));
+
+        self.globals.push(var.name());
+        self.global_indexes.insert(var, self.globals.len() - 1);
+        self.globals.len() - 1
+    }
+
+    // 関数ごとに変数の位置を割り当て直す
+    fn begin_function(&mut self, params: &[(Var, Type)]) {
+        self.slots.clear();
+        self.next_slot = 0;
+        for (i, (var, _)) in params.iter().enumerate() {
+            self.slots.insert(*var, -(i as isize) - 1);
         }
     }
+
+    fn end_function(&self, name: Option<&str>) -> Result<(), CodegenError> {
+        if self.next_slot > MAX_VARIABLES {
+            return Err(CodegenError::TooManyVariables {
+                function: name.map(|name| name.to_string()),
+                needed: self.next_slot,
+            });
+        }
+
+        Ok(())
+    }
+
+    // 文字列を定数プールに入れて、その位置を返す
+    fn string(&mut self, s: &'a str) -> usize {
+        if let Some(index) = self.string_indexes.get(s) {
+            return *index;
+        }
+
+        self.strings.push(s.to_string());
+        self.string_indexes.insert(s, self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    fn function(&mut self, func: &'a Function) -> Result<(), CodegenError> {
+        self.functions
+            .insert(&func.name, (self.insts.len(), func.params.len()));
+        self.begin_function(&func.params);
+        for stmt in &func.body {
+            self.stmt(stmt)?;
+        }
+
+        // 末尾に到達した場合は0を返す
+        self.insts.push(Inst::Int(0));
+        self.insts.push(Inst::Return);
+        self.end_function(Some(&func.name))
+    }
+
+    fn finish(mut self) -> Result<Program, CodegenError> {
+        for (index, name) in &self.calls {
+            match &mut self.insts[*index] {
+                Inst::CallFunc(loc, args) => match self.functions.get(name) {
+                    Some((_, params)) if *params != *args => {
+                        return Err(CodegenError::WrongArgumentCount {
+                            function: name.to_string(),
+                            expected: *params,
+                            found: *args,
+                        })
+                    }
+                    Some((func_loc, _)) => *loc = *func_loc,
+                    None => return Err(CodegenError::UnknownFunction(name.to_string())),
+                },
+                _ => unreachable!(),
+            }
+        }
+
+        for inst in &mut self.insts {
+            match inst {
+                Inst::Jump(loc) | Inst::JumpIfZero(loc) => {
+                    let label_loc = self.labels[loc];
+                    *loc = label_loc;
+                }
+                Inst::JumpTable(_, table, default) => {
+                    for loc in table {
+                        *loc = self.labels[loc];
+                    }
+                    *default = self.labels[default];
+                }
+                Inst::LookupSwitch(cases, default) => {
+                    for (_, loc) in cases {
+                        *loc = self.labels[loc];
+                    }
+                    *default = self.labels[default];
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Program {
+            insts: self.insts,
+            strings: self.strings,
+            globals: self.globals,
+        })
+    }
 }
 
-pub fn ir_to_insts(stmts: &[Stmt]) -> Vec<Inst> {
-    let mut labels = HashMap::new();
-    let mut insts = Vec::new();
+// 変数には使われた順にVMの変数の位置を割り当てる
+pub fn ir_to_insts(stmts: &[Stmt]) -> Result<Program, CodegenError> {
+    let mut codegen = Codegen::default();
     for stmt in stmts {
-        stmt_to_insts(&mut insts, &mut labels, stmt);
+        codegen.stmt(stmt)?;
     }
+    codegen.end_function(None)?;
 
-    for inst in &mut insts {
-        match inst {
-            Inst::Jump(loc) | Inst::JumpIfZero(loc) => {
-                let label_loc = labels[loc];
-                *loc = label_loc;
-            }
-            _ => {}
+    codegen.finish()
+}
+
+// main関数から実行が始まるように、main関数を先頭に配置する
+pub fn module_to_insts(module: &Module) -> Result<Program, CodegenError> {
+    let main = module.function("main").ok_or(CodegenError::MissingMain)?;
+
+    let mut codegen = Codegen::default();
+    codegen.function(main)?;
+    for func in &module.functions {
+        if func.name != main.name {
+            codegen.function(func)?;
         }
     }
 
-    insts
+    codegen.finish()
 }
 
-pub fn print_insts(insts: &[Inst]) {
+pub fn print_insts(program: &Program) {
+    let Program {
+        insts,
+        strings,
+        globals,
+    } = program;
+
+    if !strings.is_empty() {
+        let width = format!("{}", strings.len()).len();
+        for (i, s) in strings.iter().enumerate() {
+            println!("#{:<width$}  {:?}", i, s, width = width);
+        }
+        println!();
+    }
+
     let width = format!("{}", insts.len()).len();
 
     for (i, inst) in insts.iter().enumerate() {
         print!("{:<width$}  ", i, width = width);
         match inst {
             Inst::Int(n) => println!("INT {}", n),
+            Inst::Bool(b) => println!("BOOL {}", b),
+            Inst::Float(x) => println!("FLOAT {:?}", x),
             Inst::Add => println!("ADD"),
+            Inst::Sub => println!("SUB"),
             Inst::Mul => println!("MUL"),
+            Inst::Div => println!("DIV"),
+            Inst::Mod => println!("MOD"),
+            Inst::Eq => println!("EQ"),
+            Inst::Ne => println!("NE"),
+            Inst::Lt => println!("LT"),
+            Inst::Le => println!("LE"),
+            Inst::Gt => println!("GT"),
+            Inst::Ge => println!("GE"),
+            Inst::Not => println!("NOT"),
+            Inst::And => println!("AND"),
+            Inst::Or => println!("OR"),
+            Inst::BitAnd => println!("BIT_AND"),
+            Inst::BitOr => println!("BIT_OR"),
+            Inst::BitXor => println!("BIT_XOR"),
+            Inst::Shl => println!("SHL"),
+            Inst::Shr => println!("SHR"),
+            Inst::UShr => println!("USHR"),
+            Inst::Select => println!("SELECT"),
+            Inst::Convert(ty) => println!("CONVERT {}", ty),
             Inst::Store(loc) => println!("STORE {}", loc),
             Inst::LoadCopy(loc) => println!("LOAD_COPY {}", loc),
+            Inst::StoreGlobal(index) => println!("STORE_GLOBAL {}", globals[*index]),
+            Inst::LoadGlobal(index) => println!("LOAD_GLOBAL {}", globals[*index]),
             Inst::Jump(loc) => println!("JUMP {}", loc),
             Inst::JumpIfZero(loc) => println!("JUMP_IF_ZERO {}", loc),
-            Inst::Call(id) => match id {
-                0 => println!("PRINT"),
+            Inst::CallFunc(loc, argc) => println!("CALL_FUNC {} {}", loc, argc),
+            Inst::Return => println!("RETURN"),
+            Inst::Pop => println!("POP"),
+            Inst::Load => println!("LOAD"),
+            Inst::StoreMem => println!("STORE_MEM"),
+            Inst::Read => println!("READ"),
+            Inst::JumpTable(min, table, default) => println!(
+                "JUMP_TABLE {} [{}] default {}",
+                min,
+                FormatIter(table, ", "),
+                default
+            ),
+            Inst::LookupSwitch(cases, default) => {
+                let cases = cases
+                    .iter()
+                    .map(|(value, loc)| format!("{}: {}", value, loc));
+                println!(
+                    "LOOKUP_SWITCH [{}] default {}",
+                    FormatIter(cases, ", "),
+                    default
+                );
+            }
+            Inst::Assert(index) => println!("ASSERT #{}", index),
+            Inst::Trap(index) => println!("TRAP #{}", index),
+            Inst::PrintStr(index) => println!("PRINT_STR #{}", index),
+            Inst::PrintStrLn(index) => println!("PRINT_STR_LN #{}", index),
+            Inst::Call(id) => match *id {
+                PRINT => println!("PRINT"),
+                PRINT_NO_NEWLINE => println!("PRINT_NO_NEWLINE"),
                 _ => println!("CALL {} (unknown)", id),
             },
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrapKind {
+    DivisionByZero,
+    StackOverflow,
+    // アクセスしようとしたアドレス
+    OutOfBounds(i64),
+    EndOfInput,
+    TypeMismatch,
+    AssertionFailed(String),
+    // Trap文による中断
+    Explicit(String),
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrapKind::DivisionByZero => write!(f, "division by zero"),
+            TrapKind::StackOverflow => write!(f, "stack overflow"),
+            TrapKind::OutOfBounds(addr) => write!(f, "out of bounds memory access: {}", addr),
+            TrapKind::EndOfInput => write!(f, "end of input"),
+            TrapKind::TypeMismatch => write!(f, "type mismatch"),
+            TrapKind::AssertionFailed(message) => write!(f, "assertion failed: {}", message),
+            TrapKind::Explicit(message) => write!(f, "trap: {}", message),
+        }
+    }
+}
+
+// VMの実行を中断させたエラーと、その命令の位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub ip: usize,
+}
+
+impl Trap {
+    pub fn new(kind: TrapKind, ip: usize) -> Self {
+        Self { kind, ip }
+    }
+
+    fn from_eval(err: EvalError, ip: usize) -> Self {
+        let kind = match err {
+            EvalError::DivisionByZero => TrapKind::DivisionByZero,
+            EvalError::TypeMismatch => TrapKind::TypeMismatch,
+        };
+        Self::new(kind, ip)
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.ip)
+    }
+}
+
+// 標準入力から空白で区切られた整数を読む
+// 整数として読めない値があった場合はそこで終わる
+pub fn stdin_input() -> impl Iterator<Item = i64> {
+    io::stdin()
+        .lock()
+        .lines()
+        .map_while(Result::ok)
+        .flat_map(|line| {
+            line.split_whitespace()
+                .map(|word| word.parse::<i64>())
+                .collect::<Vec<_>>()
+        })
+        .map_while(Result::ok)
+}
+
+// バイト列を一バイトずつ値として読む
+pub fn bytes_input<R: Read>(reader: R) -> impl Iterator<Item = i64> {
+    let mut reader = io::BufReader::new(reader);
+    std::iter::from_fn(move || {
+        let mut byte = [0];
+        match reader.read(&mut byte) {
+            Ok(1) => Some(byte[0] as i64),
+            _ => None,
+        }
+    })
+}
+
 const STACK_SIZE: usize = 500;
 const MAX_VARIABLES: usize = 50;
+const MAX_CALL_DEPTH: usize = 1000;
+const DEFAULT_MEMORY_SIZE: usize = 1024;
+
+// 呼び出し元のフレーム
+struct Frame {
+    return_ip: usize,
+    // 変数領域の先頭
+    fp: usize,
+    // 引数の直前のスタックの位置
+    args_base: usize,
+}
 
 pub struct VM {
-    variables: [i64; MAX_VARIABLES],
-    stack: [i64; STACK_SIZE],
+    // フレームごとにMAX_VARIABLES個ずつ確保する
+    variables: Vec<Value>,
+    stack: [Value; STACK_SIZE],
+    frames: Vec<Frame>,
+    // ワード単位でアドレスを指定する
+    memory: Vec<i64>,
+    input: Box<dyn Iterator<Item = i64>>,
+    // グローバル変数の名前と値。runをまたいで残る
+    globals: HashMap<String, Value>,
 }
 
 impl VM {
     pub fn new() -> Self {
+        Self::with_memory_size(DEFAULT_MEMORY_SIZE)
+    }
+
+    // メモリのワード数を指定して作成する
+    pub fn with_memory_size(size: usize) -> Self {
         Self {
-            variables: [0; MAX_VARIABLES],
-            stack: [0; STACK_SIZE],
+            variables: vec![Value::Int(0); MAX_VARIABLES],
+            stack: [Value::Int(0); STACK_SIZE],
+            frames: Vec::new(),
+            memory: vec![0; size],
+            input: Box::new(std::iter::empty()),
+            globals: HashMap::new(),
         }
     }
 
-    pub fn run(mut self, code: &[Inst]) {
+    // Readで読む値の入力元を設定する
+    pub fn set_input<I>(&mut self, input: I)
+    where
+        I: IntoIterator<Item = i64>,
+        I::IntoIter: 'static,
+    {
+        self.input = Box::new(input.into_iter());
+    }
+
+    fn memory_index(&self, addr: Value, ip: usize) -> Result<usize, Trap> {
+        let addr = match addr {
+            Value::Int(addr) => addr,
+            _ => return Err(Trap::new(TrapKind::TypeMismatch, ip)),
+        };
+
+        if addr < 0 || addr as usize >= self.memory.len() {
+            return Err(Trap::new(TrapKind::OutOfBounds(addr), ip));
+        }
+
+        Ok(addr as usize)
+    }
+
+    fn push(&mut self, sp: &mut usize, value: Value, ip: usize) -> Result<(), Trap> {
+        if *sp + 1 >= STACK_SIZE {
+            return Err(Trap::new(TrapKind::StackOverflow, ip));
+        }
+
+        *sp += 1;
+        self.stack[*sp] = value;
+        Ok(())
+    }
+
+    // グローバル変数の値を返す。代入されていなければNone
+    pub fn global(&self, var: Var) -> Option<Value> {
+        self.globals.get(&var.name()).copied()
+    }
+
+    // グローバル変数とメモリ以外の状態は実行ごとに初期化する
+    pub fn run(&mut self, program: &Program) -> Result<(), Trap> {
+        self.frames.clear();
+        for value in &mut self.variables[..MAX_VARIABLES] {
+            *value = Value::Int(0);
+        }
+
+        let code = &program.insts;
         let mut ip = 0;
         let mut sp = 0;
+        let mut fp = 0;
+        let mut args_base = 0;
 
         while ip < code.len() {
+            if let Some(op) = code[ip].binop() {
+                let lhs = self.stack[sp - 1];
+                let rhs = self.stack[sp];
+                self.stack[sp - 1] = op.eval(lhs, rhs).map_err(|err| Trap::from_eval(err, ip))?;
+                sp -= 1;
+
+                ip += 1;
+                continue;
+            }
+
             match &code[ip] {
-                Inst::Int(n) => {
-                    sp += 1;
-                    self.stack[sp] = *n;
+                Inst::Int(n) => self.push(&mut sp, Value::Int(*n), ip)?,
+                Inst::Bool(b) => self.push(&mut sp, Value::Bool(*b), ip)?,
+                Inst::Float(x) => self.push(&mut sp, Value::Float(*x), ip)?,
+                Inst::Not => {
+                    self.stack[sp] = self.stack[sp]
+                        .logical_not()
+                        .map_err(|err| Trap::from_eval(err, ip))?;
                 }
-                Inst::Add => {
-                    self.stack[sp - 1] += self.stack[sp];
-                    sp -= 1;
+                Inst::Convert(ty) => {
+                    self.stack[sp] = self.stack[sp].convert(*ty);
                 }
-                Inst::Mul => {
-                    self.stack[sp - 1] *= self.stack[sp];
-                    sp -= 1;
+                Inst::Select => {
+                    let cond = self.stack[sp - 2];
+                    let value = if !cond.is_zero() {
+                        self.stack[sp - 1]
+                    } else {
+                        self.stack[sp]
+                    };
+                    self.stack[sp - 2] = value;
+                    sp -= 2;
                 }
+                // 負の位置は引数を表す
                 Inst::Store(loc) => {
-                    self.variables[*loc as usize] = self.stack[sp];
+                    let value = self.stack[sp];
                     sp -= 1;
+                    if *loc >= 0 {
+                        self.variables[fp + *loc as usize] = value;
+                    } else {
+                        self.stack[args_base + (-*loc) as usize] = value;
+                    }
                 }
                 Inst::LoadCopy(loc) => {
-                    sp += 1;
-                    self.stack[sp] = self.variables[*loc as usize];
+                    let value = if *loc >= 0 {
+                        self.variables[fp + *loc as usize]
+                    } else {
+                        self.stack[args_base + (-*loc) as usize]
+                    };
+                    self.push(&mut sp, value, ip)?;
+                }
+                Inst::StoreGlobal(index) => {
+                    let value = self.stack[sp];
+                    sp -= 1;
+                    self.globals.insert(program.globals[*index].clone(), value);
+                }
+                // 代入されていないグローバル変数は0として読む
+                Inst::LoadGlobal(index) => {
+                    let value = match self.globals.get(&program.globals[*index]) {
+                        Some(value) => *value,
+                        None => Value::Int(0),
+                    };
+                    self.push(&mut sp, value, ip)?;
                 }
                 Inst::Jump(loc) => {
                     ip = *loc;
                     continue;
                 }
                 Inst::JumpIfZero(loc) => {
-                    if self.stack[sp] == 0 {
+                    let value = self.stack[sp];
+                    sp -= 1;
+                    if value.is_zero() {
                         ip = *loc;
+                        continue;
+                    }
+                }
+                Inst::CallFunc(loc, argc) => {
+                    if self.frames.len() >= MAX_CALL_DEPTH {
+                        return Err(Trap::new(TrapKind::StackOverflow, ip));
+                    }
+
+                    self.frames.push(Frame {
+                        return_ip: ip + 1,
+                        fp,
+                        args_base,
+                    });
+
+                    fp += MAX_VARIABLES;
+                    if self.variables.len() < fp + MAX_VARIABLES {
+                        self.variables.resize(fp + MAX_VARIABLES, Value::Int(0));
                     }
+                    for value in &mut self.variables[fp..fp + MAX_VARIABLES] {
+                        *value = Value::Int(0);
+                    }
+                    args_base = sp - argc;
+
+                    ip = *loc;
+                    continue;
+                }
+                Inst::Return => {
+                    let value = self.stack[sp];
+
+                    // 一番外側のフレームから戻る場合は実行を終了する
+                    let frame = match self.frames.pop() {
+                        Some(frame) => frame,
+                        None => return Ok(()),
+                    };
+
+                    // 引数を取り除いて戻り値を積む
+                    sp = args_base + 1;
+                    self.stack[sp] = value;
+
+                    ip = frame.return_ip;
+                    fp = frame.fp;
+                    args_base = frame.args_base;
+                    continue;
+                }
+                Inst::Pop => {
+                    sp -= 1;
+                }
+                Inst::Load => {
+                    let index = self.memory_index(self.stack[sp], ip)?;
+                    self.stack[sp] = Value::Int(self.memory[index]);
+                }
+                Inst::StoreMem => {
+                    let index = self.memory_index(self.stack[sp - 1], ip)?;
+                    self.memory[index] = match self.stack[sp] {
+                        Value::Int(n) => n,
+                        _ => return Err(Trap::new(TrapKind::TypeMismatch, ip)),
+                    };
+                    sp -= 2;
+                }
+                Inst::Read => match self.input.next() {
+                    Some(value) => self.push(&mut sp, Value::Int(value), ip)?,
+                    None => return Err(Trap::new(TrapKind::EndOfInput, ip)),
+                },
+                Inst::JumpTable(min, table, default) => {
+                    let value = match self.stack[sp] {
+                        Value::Int(n) => n,
+                        _ => return Err(Trap::new(TrapKind::TypeMismatch, ip)),
+                    };
+                    sp -= 1;
+
+                    let offset = value as i128 - *min as i128;
+                    ip = if offset >= 0 && offset < table.len() as i128 {
+                        table[offset as usize]
+                    } else {
+                        *default
+                    };
+                    continue;
+                }
+                Inst::LookupSwitch(cases, default) => {
+                    let value = match self.stack[sp] {
+                        Value::Int(n) => n,
+                        _ => return Err(Trap::new(TrapKind::TypeMismatch, ip)),
+                    };
                     sp -= 1;
+
+                    ip = match cases.binary_search_by_key(&value, |(value, _)| *value) {
+                        Ok(index) => cases[index].1,
+                        Err(_) => *default,
+                    };
                     continue;
                 }
+                Inst::Assert(index) => {
+                    let value = self.stack[sp];
+                    sp -= 1;
+                    if value.is_zero() {
+                        let message = program.strings[*index].clone();
+                        return Err(Trap::new(TrapKind::AssertionFailed(message), ip));
+                    }
+                }
+                Inst::Trap(index) => {
+                    let message = program.strings[*index].clone();
+                    return Err(Trap::new(TrapKind::Explicit(message), ip));
+                }
+                Inst::PrintStr(index) => print!("{}", program.strings[*index]),
+                Inst::PrintStrLn(index) => println!("{}", program.strings[*index]),
                 Inst::Call(id) => match *id {
-                    0 => {
+                    PRINT => {
                         let value = self.stack[sp];
                         sp -= 1;
                         println!("{}", value);
                     }
+                    PRINT_NO_NEWLINE => {
+                        let value = self.stack[sp];
+                        sp -= 1;
+                        print!("{}", value);
+                    }
                     _ => panic!("Unknown function id: {}", *id),
                 },
                 // #[non_exhaustive]を指定しているのに警告が出る
@@ -164,5 +971,393 @@ impl VM {
 
             ip += 1;
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir;
+    use crate::ir::{Expr::*, Stmt, Var};
+
+    #[test]
+    fn test_division_by_zero() {
+        let code = vec![Stmt::Store(
+            Var::new("x"),
+            Div(
+                Box::new(Int(10)),
+                Box::new(Sub(Box::new(Int(3)), Box::new(Int(3)))),
+            ),
+        )];
+        let insts = ir_to_insts(&code).unwrap();
+
+        let trap = VM::new().run(&insts).unwrap_err();
+        assert_eq!(trap.kind, TrapKind::DivisionByZero);
+        assert_eq!(trap.ip, 4);
+    }
+
+    #[test]
+    fn test_div_overflow() {
+        let code = vec![Stmt::Store(
+            Var::new("x"),
+            Mod(Box::new(Int(i64::MIN)), Box::new(Int(-1))),
+        )];
+        let insts = ir_to_insts(&code).unwrap();
+
+        assert!(VM::new().run(&insts).is_ok());
+    }
+
+    fn call(name: &str, args: Vec<ir::Expr>) -> ir::Expr {
+        Call(name.to_string(), args)
+    }
+
+    #[test]
+    fn test_recursion() {
+        let l0 = ir::Label::new();
+        let param = Var::new("n");
+        let n = LoadCopy(param);
+        let result = Var::global("vm_fact");
+
+        // fact(n) = if n == 0 { 1 } else { n * fact(n - 1) }
+        let fact = Function::new(
+            "fact",
+            vec![(param, Type::I64)],
+            Type::I64,
+            vec![
+                Stmt::JumpIfZero(n.clone(), l0),
+                Stmt::Return(Mul(
+                    Box::new(n.clone()),
+                    Box::new(call("fact", vec![Sub(Box::new(n), Box::new(Int(1)))])),
+                )),
+                Stmt::Label(l0),
+                Stmt::Return(Int(1)),
+            ],
+        );
+        let main = Function::new(
+            "main",
+            Vec::new(),
+            Type::I64,
+            vec![Stmt::Store(result, call("fact", vec![Int(10)]))],
+        );
+
+        let mut module = Module::new();
+        module.add(fact);
+        module.add(main);
+
+        let insts = module_to_insts(&module).unwrap();
+        let mut vm = VM::new();
+        vm.run(&insts).unwrap();
+        assert_eq!(vm.global(result), Some(Value::Int(3628800)));
+    }
+
+    #[test]
+    fn test_wrong_argument_count() {
+        let param = Var::new("n");
+        let mut module = Module::new();
+        module.add(Function::new(
+            "id",
+            vec![(param, Type::I64)],
+            Type::I64,
+            vec![Stmt::Return(LoadCopy(param))],
+        ));
+        module.add(Function::new(
+            "main",
+            Vec::new(),
+            Type::I64,
+            vec![Stmt::Print(call("id", vec![Int(1), Int(2)]))],
+        ));
+
+        assert_eq!(
+            module_to_insts(&module).unwrap_err(),
+            CodegenError::WrongArgumentCount {
+                function: "id".to_string(),
+                expected: 1,
+                found: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut module = Module::new();
+        module.add(Function::new(
+            "main",
+            Vec::new(),
+            Type::I64,
+            vec![Stmt::Return(call("main", Vec::new()))],
+        ));
+
+        let insts = module_to_insts(&module).unwrap();
+        let trap = VM::new().run(&insts).unwrap_err();
+        assert_eq!(trap.kind, TrapKind::StackOverflow);
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let x = Var::new("x");
+        let code = vec![
+            Stmt::StoreMem(Int(7), Int(1)),
+            Stmt::Store(x, Load(Box::new(Int(7)))),
+            Stmt::StoreMem(Int(8), LoadCopy(x)),
+        ];
+        let insts = ir_to_insts(&code).unwrap();
+
+        let trap = VM::with_memory_size(8).run(&insts).unwrap_err();
+        assert_eq!(trap.kind, TrapKind::OutOfBounds(8));
+        assert_eq!(trap.ip, 8);
+    }
+
+    #[test]
+    fn test_read() {
+        // 二つ目の入力で割るので、0が読まれればトラップする
+        let code = vec![Stmt::Print(Div(Box::new(Read), Box::new(Read)))];
+        let insts = ir_to_insts(&code).unwrap();
+
+        let mut vm = VM::new();
+        vm.set_input(vec![10, 2]);
+        assert_eq!(vm.run(&insts), Ok(()));
+
+        let mut vm = VM::new();
+        vm.set_input(bytes_input(&[10, 0][..]));
+        assert_eq!(vm.run(&insts).unwrap_err().kind, TrapKind::DivisionByZero);
+
+        let mut vm = VM::new();
+        vm.set_input(vec![10]);
+        assert_eq!(vm.run(&insts).unwrap_err().kind, TrapKind::EndOfInput);
+    }
+
+    // selectorで分岐した先の番号をグローバル変数に入れて返す。defaultに分岐すれば-1になる
+    fn run_switch(cases: &[i64], selector: i64) -> i64 {
+        let result = Var::global("vm_switch");
+        let end = ir::Label::new();
+        let default = ir::Label::new();
+        let labels: Vec<ir::Label> = cases.iter().map(|_| ir::Label::new()).collect();
+
+        let mut code = vec![Stmt::Switch(
+            Int(selector),
+            cases.iter().copied().zip(labels.iter().copied()).collect(),
+            default,
+        )];
+        for (i, label) in labels.iter().enumerate() {
+            code.push(Stmt::Label(*label));
+            code.push(Stmt::Store(result, Int(i as i64)));
+            code.push(Stmt::Jump(end));
+        }
+        code.push(Stmt::Label(default));
+        code.push(Stmt::Store(result, Int(-1)));
+        code.push(Stmt::Label(end));
+
+        let program = ir_to_insts(&code).unwrap();
+        let mut vm = VM::new();
+        vm.run(&program).unwrap();
+        match vm.global(result) {
+            Some(Value::Int(n)) => n,
+            value => panic!("unexpected result: {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_switch() {
+        // ジャンプテーブル
+        let dense = [3, 4, 6, 4];
+        assert_eq!(run_switch(&dense, 4), 1);
+        assert_eq!(run_switch(&dense, 6), 2);
+        assert_eq!(run_switch(&dense, 5), -1);
+        assert_eq!(run_switch(&dense, i64::MIN), -1);
+
+        // 二分探索
+        let sparse = [100, -7, 1 << 40];
+        assert_eq!(run_switch(&sparse, -7), 1);
+        assert_eq!(run_switch(&sparse, 1 << 40), 2);
+        assert_eq!(run_switch(&sparse, 0), -1);
+    }
+
+    #[test]
+    fn test_comparison_and_logical_ops() {
+        use Value::Bool as B;
+
+        let b = |expr| Box::new(expr);
+        let cases = vec![
+            (Eq(b(Int(2)), b(Int(2))), B(true)),
+            (Eq(b(Float(1.5)), b(Float(2.5))), B(false)),
+            (Ne(b(Bool(true)), b(Bool(false))), B(true)),
+            (Lt(b(Int(-1)), b(Int(0))), B(true)),
+            (Lt(b(Float(2.0)), b(Float(1.0))), B(false)),
+            (Le(b(Int(3)), b(Int(3))), B(true)),
+            (Gt(b(Int(3)), b(Int(3))), B(false)),
+            (Ge(b(Float(3.0)), b(Float(3.0))), B(true)),
+            (Not(b(Bool(false))), B(true)),
+            (And(b(Bool(true)), b(Bool(false))), B(false)),
+            (Or(b(Bool(false)), b(Bool(true))), B(true)),
+            // 整数の論理演算は0か1になる
+            (Not(b(Int(0))), Value::Int(1)),
+            (Not(b(Int(-5))), Value::Int(0)),
+            (And(b(Int(2)), b(Int(-3))), Value::Int(1)),
+            (And(b(Int(2)), b(Int(0))), Value::Int(0)),
+            (Or(b(Int(0)), b(Int(7))), Value::Int(1)),
+            (Or(b(Int(0)), b(Int(0))), Value::Int(0)),
+        ];
+
+        let result = Var::global("vm_compare");
+        for (expr, expected) in cases {
+            assert_eq!(expr.eval_const(), Some(expected));
+
+            let code = vec![Stmt::Store(result, expr)];
+            let mut vm = VM::new();
+            vm.run(&ir_to_insts(&code).unwrap()).unwrap();
+            assert_eq!(vm.global(result), Some(expected));
+        }
+
+        // 整数と真偽値は混ぜられない
+        let code = vec![Stmt::Store(result, And(b(Int(1)), b(Bool(true))))];
+        let trap = VM::new().run(&ir_to_insts(&code).unwrap()).unwrap_err();
+        assert_eq!(trap.kind, TrapKind::TypeMismatch);
+    }
+
+    #[test]
+    fn test_shift_out_of_range() {
+        // シフト量は64で割った余りになり、定数畳み込みとVMで結果が同じになる
+        let b = |n| Box::new(Int(n));
+        let cases = vec![
+            (Shl(b(1), b(64)), 1),
+            (Shl(b(1), b(65)), 2),
+            (Shl(b(1), b(-1)), i64::MIN),
+            (Shr(b(-8), b(65)), -4),
+            (Shr(b(i64::MIN), b(127)), -1),
+            (UShr(b(-1), b(127)), 1),
+            (UShr(b(-8), b(64)), -8),
+        ];
+
+        let result = Var::global("vm_shift");
+        for (expr, expected) in cases {
+            assert_eq!(expr.eval_const(), Some(Value::Int(expected)));
+
+            let code = vec![Stmt::Store(result, expr)];
+            let mut vm = VM::new();
+            vm.run(&ir_to_insts(&code).unwrap()).unwrap();
+            assert_eq!(vm.global(result), Some(Value::Int(expected)));
+        }
+    }
+
+    #[test]
+    fn test_assert() {
+        let code = vec![
+            Stmt::Assert(Bool(true), "unreachable".to_string()),
+            Stmt::Assert(Lt(Box::new(Int(3)), Box::new(Int(2))), "3 < 2".to_string()),
+        ];
+        let program = ir_to_insts(&code).unwrap();
+
+        let trap = VM::new().run(&program).unwrap_err();
+        assert_eq!(trap.kind, TrapKind::AssertionFailed("3 < 2".to_string()));
+        assert_eq!(trap.ip, 5);
+    }
+
+    #[test]
+    fn test_string_pool() {
+        let code = vec![
+            Stmt::PrintNoNewline(Str("x = ".to_string())),
+            Stmt::Print(Int(1)),
+            Stmt::Print(Str("done".to_string())),
+            Stmt::PrintNoNewline(Str("x = ".to_string())),
+            Stmt::Assert(Bool(true), "done".to_string()),
+        ];
+        let program = ir_to_insts(&code).unwrap();
+
+        // 同じ文字列はプールに一度だけ入る
+        assert_eq!(
+            program.strings,
+            vec!["x = ".to_string(), "done".to_string()]
+        );
+        assert!(matches!(
+            program.insts[..],
+            [
+                Inst::PrintStr(0),
+                Inst::Int(1),
+                Inst::Call(PRINT),
+                Inst::PrintStrLn(1),
+                Inst::PrintStr(0),
+                Inst::Bool(true),
+                Inst::Assert(1),
+                ..
+            ]
+        ));
+        assert_eq!(VM::new().run(&program), Ok(()));
+    }
+
+    #[test]
+    fn test_unexpected_string() {
+        let code = vec![Stmt::Store(Var::new("s"), Str("s".to_string()))];
+        assert_eq!(
+            ir_to_insts(&code).unwrap_err(),
+            CodegenError::UnexpectedString
+        );
+
+        let code = vec![Stmt::Print(Add(
+            Box::new(Str("s".to_string())),
+            Box::new(Int(1)),
+        ))];
+        assert_eq!(
+            ir_to_insts(&code).unwrap_err(),
+            CodegenError::UnexpectedString
+        );
+    }
+
+    #[test]
+    fn test_not_lowered() {
+        let code = vec![Stmt::While(Bool(true), vec![Stmt::Break])];
+        assert!(matches!(
+            ir_to_insts(&code).unwrap_err(),
+            CodegenError::NotLowered(_)
+        ));
+        assert_eq!(
+            ir_to_insts(&[Stmt::Continue]).unwrap_err(),
+            CodegenError::NotLowered(Stmt::Continue.to_string())
+        );
+    }
+
+    #[test]
+    fn test_too_many_variables() {
+        let vars: Vec<Var> = (0..MAX_VARIABLES + 1).map(|_| Var::new("t")).collect();
+        let mut code: Vec<Stmt> = vars[1..]
+            .iter()
+            .map(|var| Stmt::Store(*var, Int(1)))
+            .collect();
+        assert!(ir_to_insts(&code).is_ok());
+
+        code.push(Stmt::Print(LoadCopy(vars[0])));
+        assert_eq!(
+            ir_to_insts(&code).unwrap_err(),
+            CodegenError::TooManyVariables {
+                function: None,
+                needed: MAX_VARIABLES + 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_global_persists_across_runs() {
+        let count = Var::global("count");
+        let tmp = Var::new("tmp");
+        let code = vec![
+            Stmt::Store(tmp, Add(Box::new(LoadCopy(count)), Box::new(Int(1)))),
+            Stmt::Store(count, LoadCopy(tmp)),
+        ];
+        let program = ir_to_insts(&code).unwrap();
+        assert_eq!(program.globals, vec!["@count".to_string()]);
+
+        let mut vm = VM::new();
+        assert_eq!(vm.global(count), None);
+        vm.run(&program).unwrap();
+        vm.run(&program).unwrap();
+        assert_eq!(vm.global(count), Some(Value::Int(2)));
+
+        // 同じ名前のグローバル変数は別のProgramからも同じ値になる
+        let code = vec![Stmt::Assert(
+            Eq(Box::new(LoadCopy(Var::global("count"))), Box::new(Int(2))),
+            "count".to_string(),
+        )];
+        assert_eq!(vm.run(&ir_to_insts(&code).unwrap()), Ok(()));
     }
 }